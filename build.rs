@@ -1,4 +1,6 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use object::{Object, ObjectSymbol, SymbolKind};
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -7,15 +9,21 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    // the kernel symbols travel as the ramdisk, to symbolize backtraces
+    let symbols_path = out_dir.join("kernel.sym");
+    write_symbol_table(&kernel, &symbols_path);
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&symbols_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&symbols_path)
         .create_disk_image(&bios_path)
         .unwrap();
 
@@ -26,3 +34,40 @@ fn main() {
     println!("cargo:warning=UEFI: {}", uefi_path.display());
     println!("cargo:warning=BIOS: {}", bios_path.display());
 }
+
+/// Writes the function symbols of the kernel ELF, demangled and sorted by address, in the
+/// format `kernel/src/symbols.rs` reads:
+///
+/// - `b"KSYM"` followed by the number of entries as a little endian `u32`
+/// - per entry the address (`u64`), size, name offset and name length (`u32` each)
+/// - the names, concatenated
+fn write_symbol_table(kernel: &Path, out: &Path) {
+    let data = std::fs::read(kernel).unwrap();
+    let elf = object::File::parse(&*data).unwrap();
+
+    let mut symbols: Vec<(u64, u64, String)> = elf
+        .symbols()
+        .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+        .filter_map(|symbol| {
+            let name = rustc_demangle::demangle(symbol.name().ok()?);
+            Some((symbol.address(), symbol.size(), format!("{name:#}")))
+        })
+        .collect();
+    symbols.sort_by_key(|(address, ..)| *address);
+    symbols.dedup_by_key(|(address, ..)| *address);
+
+    let mut table = Vec::new();
+    let mut names = Vec::new();
+    table.extend_from_slice(b"KSYM");
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    for (address, size, name) in &symbols {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&(u32::try_from(*size).unwrap_or(u32::MAX)).to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+
+    std::fs::write(out, table).unwrap();
+}
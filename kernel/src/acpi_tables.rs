@@ -0,0 +1,167 @@
+use core::ptr::NonNull;
+
+use acpi::{AcpiTables, PhysicalMapping};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{mem, once_lock::OnceLock};
+
+/// Physical address of the RSDP, as reported by the bootloader
+static RSDP: OnceLock<usize> = OnceLock::new();
+
+/// Maps ACPI tables through the bootloader's physical memory mapping, so there is nothing to map
+/// or unmap, only offsets to compute
+#[derive(Debug, Clone, Copy)]
+pub struct KernelAcpiHandler;
+
+impl acpi::AcpiHandler for KernelAcpiHandler {
+	unsafe fn map_physical_region<T>(
+		&self,
+		physical_address: usize,
+		size: usize,
+	) -> PhysicalMapping<Self, T> {
+		let virt = mem::phys_to_virt(PhysAddr::new(physical_address as u64));
+		PhysicalMapping::new(
+			physical_address,
+			NonNull::new(virt.as_mut_ptr()).expect("ACPI table mapped at null"),
+			size,
+			size,
+			*self,
+		)
+	}
+
+	fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
+}
+
+/// PCI configuration mechanism #1, the address goes to the first port and the data moves
+/// through the second
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+/// Writes to the POST code port take about a microsecond, the classic I/O delay
+const IO_DELAY_PORT: u16 = 0x80;
+
+impl KernelAcpiHandler {
+	fn read<T: Copy>(address: usize) -> T {
+		unsafe { mem::phys_to_virt(PhysAddr::new(address as u64)).as_ptr::<T>().read_volatile() }
+	}
+
+	fn write<T: Copy>(address: usize, value: T) {
+		let ptr = mem::phys_to_virt(PhysAddr::new(address as u64)).as_mut_ptr::<T>();
+		unsafe { ptr.write_volatile(value) };
+	}
+
+	/// Selects the dword of the configuration space holding `offset` and returns the data port
+	/// offset of the byte asked for. Only segment 0 is reachable through the ports
+	fn pci_select(bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+		let address = 1 << 31
+			| (bus as u32) << 16
+			| (device as u32 & 0x1F) << 11
+			| (function as u32 & 0x7) << 8
+			| (offset as u32 & 0xFC);
+		unsafe { Port::<u32>::new(PCI_CONFIG_ADDRESS).write(address) };
+		PCI_CONFIG_DATA + (offset & 0b11)
+	}
+}
+
+/// Lets the `aml` interpreter reach the memory, I/O ports and PCI configuration space that
+/// operation regions refer to
+impl aml::Handler for KernelAcpiHandler {
+	fn read_u8(&self, address: usize) -> u8 { Self::read(address) }
+
+	fn read_u16(&self, address: usize) -> u16 { Self::read(address) }
+
+	fn read_u32(&self, address: usize) -> u32 { Self::read(address) }
+
+	fn read_u64(&self, address: usize) -> u64 { Self::read(address) }
+
+	fn write_u8(&mut self, address: usize, value: u8) { Self::write(address, value) }
+
+	fn write_u16(&mut self, address: usize, value: u16) { Self::write(address, value) }
+
+	fn write_u32(&mut self, address: usize, value: u32) { Self::write(address, value) }
+
+	fn write_u64(&mut self, address: usize, value: u64) { Self::write(address, value) }
+
+	fn read_io_u8(&self, port: u16) -> u8 { unsafe { Port::new(port).read() } }
+
+	fn read_io_u16(&self, port: u16) -> u16 { unsafe { Port::new(port).read() } }
+
+	fn read_io_u32(&self, port: u16) -> u32 { unsafe { Port::new(port).read() } }
+
+	fn write_io_u8(&self, port: u16, value: u8) { unsafe { Port::new(port).write(value) } }
+
+	fn write_io_u16(&self, port: u16, value: u16) { unsafe { Port::new(port).write(value) } }
+
+	fn write_io_u32(&self, port: u16, value: u32) { unsafe { Port::new(port).write(value) } }
+
+	fn read_pci_u8(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+		let port = Self::pci_select(bus, device, function, offset);
+		unsafe { Port::new(port).read() }
+	}
+
+	fn read_pci_u16(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+		let port = Self::pci_select(bus, device, function, offset);
+		unsafe { Port::new(port).read() }
+	}
+
+	fn read_pci_u32(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+		let port = Self::pci_select(bus, device, function, offset);
+		unsafe { Port::new(port).read() }
+	}
+
+	fn write_pci_u8(
+		&self,
+		_segment: u16,
+		bus: u8,
+		device: u8,
+		function: u8,
+		offset: u16,
+		value: u8,
+	) {
+		let port = Self::pci_select(bus, device, function, offset);
+		unsafe { Port::new(port).write(value) }
+	}
+
+	fn write_pci_u16(
+		&self,
+		_segment: u16,
+		bus: u8,
+		device: u8,
+		function: u8,
+		offset: u16,
+		value: u16,
+	) {
+		let port = Self::pci_select(bus, device, function, offset);
+		unsafe { Port::new(port).write(value) }
+	}
+
+	fn write_pci_u32(
+		&self,
+		_segment: u16,
+		bus: u8,
+		device: u8,
+		function: u8,
+		offset: u16,
+		value: u32,
+	) {
+		let port = Self::pci_select(bus, device, function, offset);
+		unsafe { Port::new(port).write(value) }
+	}
+
+	/// AML runs with interrupts disabled on the way down, so waits cannot use the timer
+	fn stall(&self, microseconds: u64) {
+		for _ in 0..microseconds {
+			unsafe { Port::<u8>::new(IO_DELAY_PORT).write(0) };
+		}
+	}
+
+	fn sleep(&self, milliseconds: u64) { self.stall(milliseconds.saturating_mul(1000)) }
+}
+
+/// Records where the RSDP is, later calls keep the first address
+pub fn init(rsdp_addr: u64) { RSDP.get_or_init(|| rsdp_addr as usize); }
+
+/// Parses the ACPI tables, returns `None` when the firmware did not provide them
+pub fn tables() -> Option<AcpiTables<KernelAcpiHandler>> {
+	let rsdp = *RSDP.get()?;
+	unsafe { AcpiTables::from_rsdp(KernelAcpiHandler, rsdp) }.ok()
+}
@@ -1,6 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
 use x86_64::{
+	instructions::interrupts,
 	structures::paging::{
 		mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
 	},
@@ -10,17 +10,23 @@ use x86_64::{
 use linked_list_allocator::LockedHeap;
 
 #[global_allocator]
-pub static ALLOCATOR: LockedHeap = LockedHeap::empty();
+pub static ALLOCATOR: Allocator = Allocator { heap: LockedHeap::empty() };
 
-// pub struct Allocator;
+/// The heap behind a lock only taken with interrupts disabled. A thread preempted while holding
+/// it would leave code allocating with interrupts disabled spinning forever
+pub struct Allocator {
+	heap: LockedHeap,
+}
 
-// unsafe impl GlobalAlloc for Allocator {
-// 	unsafe fn alloc(&self, _layout: Layout) -> *mut u8 { null_mut() }
+unsafe impl GlobalAlloc for Allocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		interrupts::without_interrupts(|| self.heap.alloc(layout))
+	}
 
-// 	unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-// 		panic!("dealloc should be never called")
-// 	}
-// }
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		interrupts::without_interrupts(|| self.heap.dealloc(ptr, layout))
+	}
+}
 
 pub const HEAP_START: u64 = 0x4444_4444;
 /// 1 MiB
@@ -28,7 +34,7 @@ pub const HEAP_SIZE: u64 = 1 * (1024u64.pow(2u32));
 
 pub fn init_alloc() {
 	unsafe {
-		ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE as usize);
+		ALLOCATOR.heap.lock().init(HEAP_START as *mut u8, HEAP_SIZE as usize);
 	}
 }
 
@@ -1,73 +1,105 @@
-// use acpi::{AcpiTables, PhysicalMapping};
-// use x86_64::VirtAddr;
-
-// use crate::once_lock::OnceLock;
-
-// #[repr(C)]
-// enum Rspd {
-// 	Version1(RsdpV1),
-// 	Version2(RsdpV2),
-// }
-
-// #[repr(C)]
-// #[repr(packed)]
-// struct RsdpV1 {
-// 	signature: [u8; 8],
-// 	checksumm: u8,
-// 	oemid: [u8; 6],
-// 	revision: u8,
-// 	rsdt_address: u32,
-// }
-
-// #[repr(C)]
-// struct RsdpV2 {
-// 	signature: [u8; 8],
-// 	checksumm: u8,
-// 	oemid: [u8; 6],
-// 	revision: u8,
-// 	rsdt_address: u32,
-// 	length: u32,
-// 	xsdt_address: u64,
-// 	extended_checksum: u8,
-// 	reserved: [u8; 3],
-// }
-
-use core::{alloc::Layout, ops::DerefMut};
-
-use acpi::AcpiTables;
-use alloc::sync::Arc;
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB};
-
-use crate::{allocator::ALLOCATOR, mutex::Mutex, once_lock::OnceLock};
-
-#[derive(Clone)]
-struct AcpiHandler<M, FA>
-where FA: FrameAllocator<Size4KiB>,
-    M: Mapper<Size4KiB>
-  {
-    offset: &'static OffsetPageTable<'static>,
-    mapper: Arc<Mutex<M>>,
-    frame_allocator: Arc<Mutex<FA>>  
-}
+use x86_64::{PhysAddr, VirtAddr};
 
-impl<M: Mapper<Size4KiB> + Clone, FA: FrameAllocator<Size4KiB> + Clone> acpi::AcpiHandler for AcpiHandler<M, FA> {
-    unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> acpi::PhysicalMapping<Self, T> {
-        let address = self.offset.phys_offset() + physical_address as u64;
-        let page = Page::containing_address(address);
-		let frame = self.frame_allocator.lock().allocate_frame().unwrap();
-		let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-		unsafe { self.mapper.lock().map_to(page, frame, flags, self.frame_allocator.lock().deref_mut()).unwrap().flush() };
+use crate::{interrupts::InterruptIndex, mem, once_lock::OnceLock, time};
 
-		acpi::PhysicalMapping::new(physical_address, address, Size4KiB, 1,  )
-    }
+pub static LAPIC: OnceLock<LocalApic> = OnceLock::new();
 
-    fn unmap_physical_region<T>(region: &acpi::PhysicalMapping<Self, T>) {
-        todo!()
-    }
+/// Memory mapped local APIC of the current core. Every core sees its own APIC at the same
+/// physical address, so a single instance serves all of them
+#[derive(Debug)]
+pub struct LocalApic {
+	base: VirtAddr,
 }
 
-static RSPD: OnceLock<AcpiTables<AcpiHandler>> = OnceLock::new();
+impl LocalApic {
+	/// How long [`LocalApic::calibrate_timer`] measures for
+	const CALIBRATION_MS: u64 = 10;
+	const EOI: usize = 0xB0;
+	const ESR: usize = 0x280;
+	const ICR_HIGH: usize = 0x310;
+	const ICR_LOW: usize = 0x300;
+	const ID: usize = 0x20;
+	const LVT_TIMER: usize = 0x320;
+	const SVR: usize = 0xF0;
+	const TIMER_CURRENT: usize = 0x390;
+	const TIMER_DIVIDE: usize = 0x3E0;
+	/// Divide configuration for a timer counting at a sixteenth of the bus clock
+	const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+	const TIMER_INITIAL: usize = 0x380;
+	const TIMER_MASKED: u32 = 1 << 16;
+	const TIMER_PERIODIC: u32 = 1 << 17;
+	const TPR: usize = 0x80;
+
+	/// # Safety
+	///
+	/// `phys` must be the local APIC base from the MADT and `mem::init` must have been called
+	pub unsafe fn new(phys: u64) -> LocalApic {
+		LocalApic { base: mem::phys_to_virt(PhysAddr::new(phys)) }
+	}
+
+	fn read(&self, reg: usize) -> u32 {
+		unsafe { core::ptr::read_volatile((self.base + reg as u64).as_ptr::<u32>()) }
+	}
+
+	fn write(&self, reg: usize, value: u32) {
+		unsafe { core::ptr::write_volatile((self.base + reg as u64).as_mut_ptr::<u32>(), value) }
+	}
+
+	/// Software enables the APIC of the calling core and routes its spurious interrupts
+	pub fn enable(&self) {
+		self.write(Self::TPR, 0);
+		self.write(Self::SVR, 0x100 | InterruptIndex::ApicSpurious as u32);
+	}
+
+	pub fn id(&self) -> u32 { self.read(Self::ID) >> 24 }
+
+	pub fn eoi(&self) { self.write(Self::EOI, 0); }
+
+	/// Writes the interrupt command register and waits until the APIC accepted the IPI
+	pub fn send_ipi(&self, apic_id: u32, command: u32) {
+		self.write(Self::ESR, 0);
+		self.write(Self::ICR_HIGH, apic_id << 24);
+		self.write(Self::ICR_LOW, command);
+		while self.read(Self::ICR_LOW) & (1 << 12) != 0 {
+			core::hint::spin_loop()
+		}
+	}
+
+	/// Measures how many counts the timer of the calling core goes through per millisecond,
+	/// against the PIT. Takes a little over [`LocalApic::CALIBRATION_MS`], the boot processor
+	/// must be taking timer interrupts
+	pub fn calibrate_timer(&self) -> u32 {
+		self.write(Self::TIMER_DIVIDE, Self::TIMER_DIVIDE_BY_16);
+		self.write(Self::LVT_TIMER, Self::TIMER_MASKED);
+
+		// Start right on a tick edge
+		let start = time::ticks();
+		while time::ticks() == start {
+			core::hint::spin_loop();
+		}
+		self.write(Self::TIMER_INITIAL, u32::MAX);
+		let end = start + 1 + Self::CALIBRATION_MS * time::TIMER_HZ / 1000;
+		while time::ticks() < end {
+			core::hint::spin_loop();
+		}
+		let elapsed = u32::MAX - self.read(Self::TIMER_CURRENT);
+		self.write(Self::TIMER_INITIAL, 0);
+		elapsed / Self::CALIBRATION_MS as u32
+	}
+
+	/// Makes the timer of the calling core raise `vector` every `counts`, as measured by
+	/// [`LocalApic::calibrate_timer`]
+	pub fn start_periodic_timer(&self, vector: u8, counts: u32) {
+		self.write(Self::TIMER_DIVIDE, Self::TIMER_DIVIDE_BY_16);
+		self.write(Self::LVT_TIMER, Self::TIMER_PERIODIC | vector as u32);
+		self.write(Self::TIMER_INITIAL, counts);
+	}
+
+	/// Asserts INIT on the target core, leaving it waiting for a startup IPI
+	pub fn send_init(&self, apic_id: u32) { self.send_ipi(apic_id, 0x0000_4500); }
 
-pub fn init_rspd(addr: VirtAddr) {
-	unsafe { AcpiTables::from_rsdp(PhysicalMapping::new(addr.as_u64(), , , , ), addr.as_u64()) }
+	/// Starts the target core in real mode at physical address `page << 12`
+	pub fn send_startup(&self, apic_id: u32, page: u8) {
+		self.send_ipi(apic_id, 0x0000_4600 | page as u32);
+	}
 }
@@ -0,0 +1,79 @@
+use core::{cell::UnsafeCell, task::Waker};
+
+use crate::sync::atomic::{AtomicU8, Ordering};
+
+/// Slot for the waker of the one task waiting on an event an interrupt handler signals. Neither
+/// side takes a lock, and [`AtomicWaker::wake`] neither clones nor drops a waker, so it cannot
+/// end up in the allocator from interrupt context
+pub struct AtomicWaker {
+	state: AtomicU8,
+	waker: UnsafeCell<Option<Waker>>,
+}
+
+impl AtomicWaker {
+	const REGISTERING: u8 = 1;
+	const WAITING: u8 = 0;
+	/// Set while a wake runs, or came in while registering
+	const WAKING: u8 = 2;
+
+	pub const fn new() -> AtomicWaker {
+		AtomicWaker { state: AtomicU8::new(Self::WAITING), waker: UnsafeCell::new(None) }
+	}
+
+	/// Stores `waker` to be woken by the next [`AtomicWaker::wake`]. Only one task may register
+	/// on the same slot, and it must check for the event again afterwards
+	pub fn register(&self, waker: &Waker) {
+		let stored = self.state.compare_exchange(
+			Self::WAITING,
+			Self::REGISTERING,
+			Ordering::Acquire,
+			Ordering::Acquire,
+		);
+
+		match stored {
+			Ok(_) => {
+				let old = unsafe { (*self.waker.get()).replace(waker.clone()) };
+				let released = self.state.compare_exchange(
+					Self::REGISTERING,
+					Self::WAITING,
+					Ordering::AcqRel,
+					Ordering::Acquire,
+				);
+				if released.is_err() {
+					// The wake came in while registering and left the waking to us
+					self.state.store(Self::WAITING, Ordering::Release);
+					waker.wake_by_ref();
+				}
+				drop(old);
+			}
+			// A wake is running, it may have missed this waker
+			Err(_) => waker.wake_by_ref(),
+		}
+	}
+
+	/// Wakes the registered task, if any. Safe to call from interrupt handlers
+	pub fn wake(&self) {
+		if self.state.fetch_or(Self::WAKING, Ordering::AcqRel) != Self::WAITING {
+			return;
+		}
+
+		if let Some(waker) = unsafe { (*self.waker.get()).as_ref() } {
+			waker.wake_by_ref();
+		}
+		self.state.fetch_and(!Self::WAKING, Ordering::Release);
+	}
+}
+
+impl Default for AtomicWaker {
+	fn default() -> Self { Self::new() }
+}
+
+impl core::fmt::Debug for AtomicWaker {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("AtomicWaker").field("state", &self.state).finish_non_exhaustive()
+	}
+}
+
+/// The waker is only touched by whoever moved the state away from `WAITING`
+unsafe impl Send for AtomicWaker {}
+unsafe impl Sync for AtomicWaker {}
@@ -0,0 +1,82 @@
+use core::arch::asm;
+
+use x86_64::VirtAddr;
+
+use crate::{mem, println, symbols};
+
+/// Frames printed at most, in case the chain loops
+const MAX_DEPTH: usize = 64;
+
+/// Layout every function prologue leaves on the stack when frame pointers are enabled
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Frame {
+	rbp: u64,
+	return_address: u64,
+}
+
+/// Prints the return addresses of the calling stack
+#[inline(never)]
+pub fn print() {
+	let rbp: u64;
+	unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+	print_from(rbp);
+}
+
+/// Prints the chain of return addresses starting at the frame pointed to by `rbp`. Stops at the
+/// first null, misaligned or unmapped frame
+pub fn print_from(rbp: u64) {
+	println!("Backtrace:");
+	walk(rbp, 0);
+}
+
+/// Prints the backtrace of the code an exception interrupted, from the faulting instruction at
+/// `instruction_pointer` and the frame pointer it had. The frame of the handler itself holds
+/// the CPU pushed frame where a return address would be, a walk from it stops there
+pub fn print_from_frame(instruction_pointer: VirtAddr, rbp: u64) {
+	println!("Backtrace:");
+	let rip = instruction_pointer.as_u64();
+	match symbols::resolve(rip) {
+		Some((name, offset)) => println!("  #{:<2} {:#018x} {}+{:#x}", 0, rip, name, offset),
+		None => println!("  #{:<2} {:#018x}", 0, rip),
+	}
+	walk(rbp, 1);
+}
+
+fn walk(mut rbp: u64, first_depth: usize) {
+	for depth in first_depth..MAX_DEPTH {
+		let Some(frame) = read_frame(rbp) else { return };
+		if frame.return_address == 0 {
+			return;
+		}
+
+		// The return address may already belong to the next function when the call was the last
+		// instruction, look up the call itself
+		match symbols::resolve(frame.return_address - 1) {
+			Some((name, offset)) => {
+				println!(
+					"  #{:<2} {:#018x} {}+{:#x}",
+					depth,
+					frame.return_address,
+					name,
+					offset + 1
+				)
+			}
+			None => println!("  #{:<2} {:#018x}", depth, frame.return_address),
+		}
+		rbp = frame.rbp;
+	}
+	println!("  ...");
+}
+
+fn read_frame(rbp: u64) -> Option<Frame> {
+	if rbp == 0 || rbp % 8 != 0 {
+		return None;
+	}
+
+	let start = VirtAddr::try_new(rbp).ok()?;
+	let end = VirtAddr::try_new(rbp + core::mem::size_of::<Frame>() as u64 - 1).ok()?;
+	mem::translate(start)?;
+	mem::translate(end)?;
+	Some(unsafe { start.as_ptr::<Frame>().read() })
+}
@@ -0,0 +1,207 @@
+use core::cell::UnsafeCell;
+
+use crate::{
+	sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+	time,
+	wait_queue::WaitQueue,
+};
+
+/// A lock that parks the threads waiting for it instead of spinning, for critical sections that
+/// may block or take long. Unlike [`crate::mutex::Mutex`] it can only be taken by threads, never
+/// from interrupt handlers
+#[derive(Debug)]
+pub struct Mutex<T> {
+	locked: AtomicBool,
+	waiters: WaitQueue,
+	cell: UnsafeCell<T>,
+}
+
+#[derive(Debug)]
+pub struct MutexGuard<'a, T> {
+	mtx: &'a Mutex<T>,
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+	fn drop(&mut self) {
+		self.mtx.locked.store(false, Ordering::Release);
+		self.mtx.waiters.notify_one();
+	}
+}
+
+impl<T> core::ops::Deref for MutexGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &Self::Target { unsafe { &*self.mtx.cell.get() } }
+}
+
+impl<T> core::ops::DerefMut for MutexGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut Self::Target { unsafe { &mut *self.mtx.cell.get() } }
+}
+
+impl<T> Mutex<T> {
+	pub const fn new(value: T) -> Mutex<T> {
+		Mutex {
+			locked: AtomicBool::new(false),
+			waiters: WaitQueue::new(),
+			cell: UnsafeCell::new(value),
+		}
+	}
+
+	pub fn lock(&self) -> MutexGuard<'_, T> {
+		loop {
+			if let Some(guard) = self.try_lock() {
+				return guard;
+			}
+			self.waiters.wait_until(|| !self.locked.load(Ordering::Relaxed));
+		}
+	}
+
+	/// Takes the lock only if it is free right now
+	pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+		self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).ok()?;
+		Some(MutexGuard { mtx: self })
+	}
+
+	/// Sleeps for the lock for at most `timeout_ms` milliseconds
+	pub fn lock_timeout(&self, timeout_ms: u64) -> Option<MutexGuard<'_, T>> {
+		let deadline = time::uptime_ms().saturating_add(timeout_ms);
+		loop {
+			if let Some(guard) = self.try_lock() {
+				return Some(guard);
+			}
+			let left = deadline.saturating_sub(time::uptime_ms());
+			if !self.waiters.wait_until_timeout(|| !self.locked.load(Ordering::Relaxed), left) {
+				return None;
+			}
+		}
+	}
+}
+
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+
+unsafe impl<T> Send for Mutex<T> where T: Send {}
+
+/// A counting semaphore. Threads sleep in [`Semaphore::acquire`] until a permit is free, and
+/// [`Semaphore::release`] may be called from interrupt handlers, to signal completed I/O
+#[derive(Debug)]
+pub struct Semaphore {
+	permits: AtomicUsize,
+	waiters: WaitQueue,
+}
+
+impl Semaphore {
+	pub const fn new(permits: usize) -> Semaphore {
+		Semaphore { permits: AtomicUsize::new(permits), waiters: WaitQueue::new() }
+	}
+
+	pub fn acquire(&self) {
+		while !self.try_acquire() {
+			self.waiters.wait_until(|| self.available() > 0);
+		}
+	}
+
+	/// Takes a permit only if one is free right now
+	pub fn try_acquire(&self) -> bool {
+		let mut permits = self.permits.load(Ordering::Relaxed);
+		while permits > 0 {
+			let taken = self.permits.compare_exchange_weak(
+				permits,
+				permits - 1,
+				Ordering::Acquire,
+				Ordering::Relaxed,
+			);
+			match taken {
+				Ok(_) => return true,
+				Err(current) => permits = current,
+			}
+		}
+		false
+	}
+
+	/// Sleeps for a permit for at most `timeout_ms` milliseconds. Returns whether it got one
+	pub fn acquire_timeout(&self, timeout_ms: u64) -> bool {
+		let deadline = time::uptime_ms().saturating_add(timeout_ms);
+		while !self.try_acquire() {
+			let left = deadline.saturating_sub(time::uptime_ms());
+			if !self.waiters.wait_until_timeout(|| self.available() > 0, left) {
+				return false;
+			}
+		}
+		true
+	}
+
+	/// Returns a permit and wakes a thread waiting for one
+	pub fn release(&self) {
+		self.permits.fetch_add(1, Ordering::Release);
+		self.waiters.notify_one();
+	}
+
+	pub fn available(&self) -> usize { self.permits.load(Ordering::Relaxed) }
+}
+
+/// Lets threads sleep until another one changes the state a [`Mutex`] protects. Wakeups may be
+/// spurious, waiters check their condition in a loop
+#[derive(Debug)]
+pub struct Condvar {
+	/// Bumped by every notify, a waiter that sees it change before parking does not park
+	generation: AtomicU64,
+	waiters: WaitQueue,
+}
+
+impl Condvar {
+	pub const fn new() -> Condvar {
+		Condvar { generation: AtomicU64::new(0), waiters: WaitQueue::new() }
+	}
+
+	/// Unlocks `guard`'s mutex, sleeps until notified and locks it again
+	pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+		let mtx = guard.mtx;
+		let generation = self.generation.load(Ordering::Relaxed);
+		drop(guard);
+		self.waiters.wait_until(|| self.generation.load(Ordering::Relaxed) != generation);
+		mtx.lock()
+	}
+
+	/// Like [`Condvar::wait`], but wakes up after `timeout_ms` at the latest. The flag tells
+	/// whether it timed out without a notify
+	pub fn wait_timeout<'a, T>(
+		&self,
+		guard: MutexGuard<'a, T>,
+		timeout_ms: u64,
+	) -> (MutexGuard<'a, T>, bool) {
+		let mtx = guard.mtx;
+		let generation = self.generation.load(Ordering::Relaxed);
+		drop(guard);
+		let notified = self.waiters.wait_until_timeout(
+			|| self.generation.load(Ordering::Relaxed) != generation,
+			timeout_ms,
+		);
+		(mtx.lock(), !notified)
+	}
+
+	/// Sleeps while `condition` holds for the protected value
+	pub fn wait_while<'a, T>(
+		&self,
+		mut guard: MutexGuard<'a, T>,
+		mut condition: impl FnMut(&mut T) -> bool,
+	) -> MutexGuard<'a, T> {
+		while condition(&mut guard) {
+			guard = self.wait(guard);
+		}
+		guard
+	}
+
+	pub fn notify_one(&self) {
+		self.generation.fetch_add(1, Ordering::Relaxed);
+		self.waiters.notify_one();
+	}
+
+	pub fn notify_all(&self) {
+		self.generation.fetch_add(1, Ordering::Relaxed);
+		self.waiters.notify_all();
+	}
+}
+
+impl Default for Condvar {
+	fn default() -> Self { Self::new() }
+}
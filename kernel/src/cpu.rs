@@ -0,0 +1,203 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count, CpuidResult};
+
+use crate::{once_lock::Lazy, print, println};
+
+/// Processor the kernel booted on, every core is assumed to report the same
+pub static CPU: Lazy<CpuInfo> = Lazy::new(CpuInfo::detect);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Features {
+	pub fpu: bool,
+	pub tsc: bool,
+	pub mce: bool,
+	pub apic: bool,
+	pub mca: bool,
+	pub fxsr: bool,
+	pub sse: bool,
+	pub sse2: bool,
+	pub sse3: bool,
+	pub ssse3: bool,
+	pub sse4_1: bool,
+	pub sse4_2: bool,
+	pub pcid: bool,
+	pub x2apic: bool,
+	pub tsc_deadline: bool,
+	pub xsave: bool,
+	pub osxsave: bool,
+	pub avx: bool,
+	pub hypervisor: bool,
+	pub fsgsbase: bool,
+	pub avx2: bool,
+	pub smep: bool,
+	pub smap: bool,
+	pub syscall: bool,
+	pub nx: bool,
+	pub page_1gb: bool,
+	pub rdtscp: bool,
+	pub invariant_tsc: bool,
+}
+
+impl Features {
+	/// Names of the features that are present, in the order `/proc/cpuinfo` would list them
+	pub fn names(&self) -> impl Iterator<Item = &'static str> {
+		[
+			(self.fpu, "fpu"),
+			(self.tsc, "tsc"),
+			(self.mce, "mce"),
+			(self.apic, "apic"),
+			(self.mca, "mca"),
+			(self.fxsr, "fxsr"),
+			(self.sse, "sse"),
+			(self.sse2, "sse2"),
+			(self.syscall, "syscall"),
+			(self.nx, "nx"),
+			(self.page_1gb, "pdpe1gb"),
+			(self.rdtscp, "rdtscp"),
+			(self.invariant_tsc, "constant_tsc"),
+			(self.sse3, "pni"),
+			(self.ssse3, "ssse3"),
+			(self.pcid, "pcid"),
+			(self.sse4_1, "sse4_1"),
+			(self.sse4_2, "sse4_2"),
+			(self.x2apic, "x2apic"),
+			(self.tsc_deadline, "tsc_deadline_timer"),
+			(self.xsave, "xsave"),
+			(self.osxsave, "osxsave"),
+			(self.avx, "avx"),
+			(self.hypervisor, "hypervisor"),
+			(self.fsgsbase, "fsgsbase"),
+			(self.avx2, "avx2"),
+			(self.smep, "smep"),
+			(self.smap, "smap"),
+		]
+		.into_iter()
+		.filter_map(|(present, name)| present.then_some(name))
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+	vendor: [u8; 12],
+	brand: [u8; 48],
+	pub family: u32,
+	pub model: u32,
+	pub stepping: u32,
+	pub max_leaf: u32,
+	pub max_extended_leaf: u32,
+	pub features: Features,
+}
+
+impl CpuInfo {
+	pub fn detect() -> CpuInfo {
+		let CpuidResult { eax: max_leaf, ebx, ecx, edx } = cpuid(0);
+		let mut vendor = [0; 12];
+		vendor[0..4].copy_from_slice(&ebx.to_le_bytes());
+		vendor[4..8].copy_from_slice(&edx.to_le_bytes());
+		vendor[8..12].copy_from_slice(&ecx.to_le_bytes());
+
+		let max_extended_leaf = cpuid(0x8000_0000).eax;
+		let leaf1 = cpuid(1);
+		let leaf7 = if max_leaf >= 7 { cpuid_count(7, 0) } else { empty() };
+		let ext1 = if max_extended_leaf >= 0x8000_0001 { cpuid(0x8000_0001) } else { empty() };
+		let ext7 = if max_extended_leaf >= 0x8000_0007 { cpuid(0x8000_0007) } else { empty() };
+
+		let mut brand = [0; 48];
+		if max_extended_leaf >= 0x8000_0004 {
+			for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+				let CpuidResult { eax, ebx, ecx, edx } = cpuid(leaf);
+				for (j, reg) in [eax, ebx, ecx, edx].into_iter().enumerate() {
+					let at = i * 16 + j * 4;
+					brand[at..at + 4].copy_from_slice(&reg.to_le_bytes());
+				}
+			}
+		}
+
+		let bit = |reg: u32, bit: u32| reg & (1 << bit) != 0;
+		let features = Features {
+			fpu: bit(leaf1.edx, 0),
+			tsc: bit(leaf1.edx, 4),
+			mce: bit(leaf1.edx, 7),
+			apic: bit(leaf1.edx, 9),
+			mca: bit(leaf1.edx, 14),
+			fxsr: bit(leaf1.edx, 24),
+			sse: bit(leaf1.edx, 25),
+			sse2: bit(leaf1.edx, 26),
+			sse3: bit(leaf1.ecx, 0),
+			ssse3: bit(leaf1.ecx, 9),
+			pcid: bit(leaf1.ecx, 17),
+			sse4_1: bit(leaf1.ecx, 19),
+			sse4_2: bit(leaf1.ecx, 20),
+			x2apic: bit(leaf1.ecx, 21),
+			tsc_deadline: bit(leaf1.ecx, 24),
+			xsave: bit(leaf1.ecx, 26),
+			osxsave: bit(leaf1.ecx, 27),
+			avx: bit(leaf1.ecx, 28),
+			hypervisor: bit(leaf1.ecx, 31),
+			fsgsbase: bit(leaf7.ebx, 0),
+			avx2: bit(leaf7.ebx, 5),
+			smep: bit(leaf7.ebx, 7),
+			smap: bit(leaf7.ebx, 20),
+			syscall: bit(ext1.edx, 11),
+			nx: bit(ext1.edx, 20),
+			page_1gb: bit(ext1.edx, 26),
+			rdtscp: bit(ext1.edx, 27),
+			invariant_tsc: bit(ext7.edx, 8),
+		};
+
+		let base_family = (leaf1.eax >> 8) & 0xF;
+		let base_model = (leaf1.eax >> 4) & 0xF;
+		let family = match base_family {
+			0xF => base_family + ((leaf1.eax >> 20) & 0xFF),
+			_ => base_family,
+		};
+		let model = match base_family {
+			0x6 | 0xF => base_model + (((leaf1.eax >> 16) & 0xF) << 4),
+			_ => base_model,
+		};
+
+		CpuInfo {
+			vendor,
+			brand,
+			family,
+			model,
+			stepping: leaf1.eax & 0xF,
+			max_leaf,
+			max_extended_leaf,
+			features,
+		}
+	}
+
+	pub fn vendor(&self) -> &str { core::str::from_utf8(&self.vendor).unwrap_or("unknown") }
+
+	pub fn brand(&self) -> &str {
+		core::str::from_utf8(&self.brand)
+			.map(|brand| brand.trim_matches(|c: char| c == '\0' || c.is_whitespace()))
+			.unwrap_or("unknown")
+	}
+}
+
+fn cpuid(leaf: u32) -> CpuidResult { unsafe { __cpuid(leaf) } }
+
+fn cpuid_count(leaf: u32, sub_leaf: u32) -> CpuidResult { unsafe { __cpuid_count(leaf, sub_leaf) } }
+
+fn empty() -> CpuidResult { CpuidResult { eax: 0, ebx: 0, ecx: 0, edx: 0 } }
+
+/// Features of the processor, detected on first use
+pub fn features() -> Features { CPU.features }
+
+pub fn print_report() {
+	let cpu = &*CPU;
+	println!(
+		"CPU: {} \"{}\" family {:#x} model {:#x} stepping {}",
+		cpu.vendor(),
+		cpu.brand(),
+		cpu.family,
+		cpu.model,
+		cpu.stepping
+	);
+	print!("Features:");
+	for name in cpu.features.names() {
+		print!(" {}", name);
+	}
+	println!();
+}
@@ -0,0 +1,175 @@
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, task::Wake};
+use core::{
+	future::Future,
+	pin::Pin,
+	ptr::null_mut,
+	sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+	task::{Context, Waker},
+};
+
+use x86_64::instructions::interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+	fn new() -> TaskId {
+		static NEXT: AtomicU64 = AtomicU64::new(0);
+		TaskId(NEXT.fetch_add(1, Ordering::Relaxed))
+	}
+}
+
+/// Tasks woken since the executor last looked, a stack of [`TaskWaker`]s linked through their
+/// `next` field. Wakers run in interrupt handlers and on any core, so pushing takes no lock and
+/// does not allocate. The executor only ever takes the whole stack, which keeps the pushes free
+/// of ABA problems, and a task is in it at most once
+#[derive(Debug)]
+struct ReadyQueue {
+	head: AtomicPtr<TaskWaker>,
+}
+
+impl ReadyQueue {
+	const fn new() -> ReadyQueue { ReadyQueue { head: AtomicPtr::new(null_mut()) } }
+
+	/// Queues `waker`, the stack owns the reference until [`ReadyQueue::take`] hands it back
+	fn push(&self, waker: Arc<TaskWaker>) {
+		let node = Arc::into_raw(waker).cast_mut();
+		let mut head = self.head.load(Ordering::Relaxed);
+		loop {
+			unsafe { (*node).next.store(head, Ordering::Relaxed) };
+			match self.head.compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+			{
+				Ok(_) => return,
+				Err(current) => head = current,
+			}
+		}
+	}
+
+	/// Takes every queued waker, in the order they were pushed
+	fn take(&self) -> impl Iterator<Item = Arc<TaskWaker>> {
+		let mut node = self.head.swap(null_mut(), Ordering::Acquire);
+		let mut oldest = null_mut();
+		while !node.is_null() {
+			let next = unsafe { (*node).next.load(Ordering::Relaxed) };
+			unsafe { (*node).next.store(oldest, Ordering::Relaxed) };
+			oldest = node;
+			node = next;
+		}
+
+		core::iter::from_fn(move || {
+			if oldest.is_null() {
+				return None;
+			}
+			let waker = unsafe { Arc::from_raw(oldest) };
+			// Read before the caller clears `queued`, a wake after that links the waker anew
+			oldest = waker.next.load(Ordering::Relaxed);
+			Some(waker)
+		})
+	}
+
+	fn is_empty(&self) -> bool { self.head.load(Ordering::Relaxed).is_null() }
+}
+
+impl Drop for ReadyQueue {
+	fn drop(&mut self) { self.take().for_each(drop) }
+}
+
+struct Task {
+	future: Pin<Box<dyn Future<Output = ()> + Send>>,
+	waker: Arc<TaskWaker>,
+}
+
+#[derive(Debug)]
+struct TaskWaker {
+	id: TaskId,
+	/// Set while the task sits in the ready queue, and for good once it finished
+	queued: AtomicBool,
+	/// Next waker in the ready queue, only meaningful while `queued` is set
+	next: AtomicPtr<TaskWaker>,
+	ready: Arc<ReadyQueue>,
+}
+
+impl TaskWaker {
+	fn schedule(self: &Arc<Self>) {
+		if !self.queued.swap(true, Ordering::AcqRel) {
+			self.ready.push(self.clone());
+		}
+	}
+}
+
+impl Wake for TaskWaker {
+	fn wake(self: Arc<Self>) { self.schedule() }
+
+	fn wake_by_ref(self: &Arc<Self>) { self.schedule() }
+}
+
+/// Runs `async` kernel tasks on the core that owns it, polling a task only after its waker was
+/// called. Interrupt handlers feed it through futures like [`crate::time::Sleep`] and
+/// [`crate::keyboard::ScancodeStream`]
+pub struct Executor {
+	tasks: BTreeMap<TaskId, Task>,
+	ready: Arc<ReadyQueue>,
+}
+
+impl Executor {
+	pub fn new() -> Executor {
+		Executor { tasks: BTreeMap::new(), ready: Arc::new(ReadyQueue::new()) }
+	}
+
+	/// Adds a task, it is first polled by the next [`Executor::run_ready_tasks`]
+	pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+		let id = TaskId::new();
+		let waker = Arc::new(TaskWaker {
+			id,
+			queued: AtomicBool::new(false),
+			next: AtomicPtr::new(null_mut()),
+			ready: self.ready.clone(),
+		});
+
+		self.tasks.insert(id, Task { future: Box::pin(future), waker: waker.clone() });
+		waker.schedule();
+		id
+	}
+
+	/// Polls every task that was woken, including the ones woken while this runs
+	pub fn run_ready_tasks(&mut self) {
+		while !self.ready.is_empty() {
+			for woken in self.ready.take() {
+				let id = woken.id;
+				let Some(task) = self.tasks.get_mut(&id) else { continue };
+
+				// Cleared before polling, so a wake during the poll queues the task again
+				task.waker.queued.store(false, Ordering::Release);
+				let waker = Waker::from(woken);
+				if task.future.as_mut().poll(&mut Context::from_waker(&waker)).is_ready() {
+					// Wakers still held elsewhere must not queue it again
+					task.waker.queued.store(true, Ordering::Relaxed);
+					self.tasks.remove(&id);
+				}
+			}
+		}
+	}
+
+	/// Halts until the next interrupt if no task is ready. Interrupts stay disabled between the
+	/// check and the `hlt`, so a handler waking a task cannot slip in between
+	pub fn sleep_if_idle(&self) {
+		interrupts::disable();
+		if self.ready.is_empty() {
+			interrupts::enable_and_hlt();
+		} else {
+			interrupts::enable();
+		}
+	}
+
+	pub fn tasks(&self) -> usize { self.tasks.len() }
+}
+
+impl Default for Executor {
+	fn default() -> Self { Self::new() }
+}
+
+impl core::fmt::Debug for Executor {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("Executor").field("tasks", &self.tasks.keys()).finish_non_exhaustive()
+	}
+}
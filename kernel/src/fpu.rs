@@ -0,0 +1,125 @@
+use core::{
+	arch::{asm, x86_64::__cpuid_count},
+	sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::registers::{
+	control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+	xcontrol::{XCr0, XCr0Flags},
+};
+
+use crate::cpu;
+
+/// Big enough for the legacy area, the XSAVE header and the AVX upper halves
+const STATE_SIZE: usize = 1024;
+/// Offsets into the legacy FXSAVE region
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+/// Whether state is saved with XSAVE, decided once by the BSP in [`init`]
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// Extended (x87, SSE and AVX) register state of a thread. A new one holds the default state,
+/// so restoring it gives a clean FPU
+#[derive(Debug, Clone)]
+#[repr(C, align(64))]
+pub struct FpuState {
+	area: [u8; STATE_SIZE],
+}
+
+impl FpuState {
+	pub const fn new() -> FpuState {
+		let mut area = [0; STATE_SIZE];
+		// x87 control word and MXCSR after `fninit`, with every exception masked
+		area[FCW_OFFSET] = 0x7F;
+		area[FCW_OFFSET + 1] = 0x03;
+		area[MXCSR_OFFSET] = 0x80;
+		area[MXCSR_OFFSET + 1] = 0x1F;
+		FpuState { area }
+	}
+
+	/// Stores the registers of the calling core into `self`
+	pub fn save(&mut self) {
+		let area = self.area.as_mut_ptr();
+		unsafe {
+			if USE_XSAVE.load(Ordering::Relaxed) {
+				asm!(
+					"xsave64 [{}]",
+					in(reg) area,
+					in("eax") u32::MAX,
+					in("edx") u32::MAX,
+					options(nostack, preserves_flags)
+				);
+			} else {
+				asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+			}
+		}
+	}
+
+	/// Loads `self` into the registers of the calling core
+	pub fn restore(&self) {
+		let area = self.area.as_ptr();
+		unsafe {
+			if USE_XSAVE.load(Ordering::Relaxed) {
+				asm!(
+					"xrstor64 [{}]",
+					in(reg) area,
+					in("eax") u32::MAX,
+					in("edx") u32::MAX,
+					options(nostack, preserves_flags, readonly)
+				);
+			} else {
+				asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags, readonly));
+			}
+		}
+	}
+}
+
+impl Default for FpuState {
+	fn default() -> Self { Self::new() }
+}
+
+/// Enables x87 and SSE on the calling core, plus AVX through XSAVE when the CPU reports it.
+/// Every core runs this once, before anything touches the extended registers.
+///
+/// The kernel itself is compiled soft-float for `x86_64-unknown-none`, so its own `f32` math
+/// never needs this. Threads, user processes and [`with_fpu`] blocks do
+pub fn init() {
+	let features = cpu::features();
+	assert!(features.fpu && features.fxsr && features.sse, "CPU lacks x87/SSE");
+
+	unsafe {
+		Cr0::update(|cr0| {
+			cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+			cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+		});
+		Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+	}
+
+	if features.xsave {
+		unsafe { Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE)) };
+		let mut xcr0 = XCr0Flags::X87 | XCr0Flags::SSE;
+		if features.avx {
+			xcr0 |= XCr0Flags::AVX;
+		}
+		unsafe { XCr0::write(xcr0) };
+
+		// EBX reports the save area size for the components enabled in XCR0
+		let size = unsafe { __cpuid_count(0xD, 0) }.ebx as usize;
+		assert!(size <= STATE_SIZE, "XSAVE area of {size} bytes does not fit FpuState");
+		USE_XSAVE.store(true, Ordering::Relaxed);
+	}
+
+	unsafe { asm!("fninit", options(nomem, nostack)) };
+}
+
+/// Runs `f` with the extended state of whatever was interrupted saved, so interrupt handlers
+/// and kernel code may use the FPU without corrupting the interrupted thread
+pub fn with_fpu<R>(f: impl FnOnce() -> R) -> R {
+	let mut saved = FpuState::new();
+	saved.save();
+	FpuState::new().restore();
+	let result = f();
+	saved.restore();
+	result
+}
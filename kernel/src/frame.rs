@@ -8,9 +8,14 @@ use noto_sans_mono_bitmap::{
 	get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
 };
 
-use crate::{mutex::Mutex, once_lock::OnceLock};
+use crate::{
+	mutex::{IrqMutex, LockStats},
+	once_lock::OnceLock,
+	percpu::{self, Console},
+};
 
-pub static WRITER: OnceLock<Mutex<FrameBufferWriter>> = OnceLock::new();
+pub static WRITER: OnceLock<IrqMutex<FrameBufferWriter>> = OnceLock::new();
+static WRITER_STATS: LockStats = LockStats::new("WRITER");
 
 /// Additional vertical space between lines
 const LINE_SPACING: usize = 2;
@@ -22,10 +27,10 @@ const BORDER_PADDING: usize = 1;
 /// The '�' character requires the feature "unicode-specials".
 const BACKUP_CHAR: char = '�';
 
-pub fn init_framebuffer(frame: &'static mut FrameBuffer) -> Mutex<FrameBufferWriter> {
+pub fn init_framebuffer(frame: &'static mut FrameBuffer) -> IrqMutex<FrameBufferWriter> {
 	let info = frame.info();
 
-	Mutex::new(FrameBufferWriter::new(frame.buffer_mut(), info))
+	IrqMutex::with_stats(FrameBufferWriter::new(frame.buffer_mut(), info), &WRITER_STATS)
 }
 
 /// Returns the raster of the given char or the raster of [`font_constants::BACKUP_CHAR`].
@@ -254,8 +259,7 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
 	use core::fmt::Write;
-	x86_64::instructions::interrupts::without_interrupts(|| {
-		// new
-		WRITER.get().unwrap().lock().write_fmt(args).unwrap();
-	});
+	// This core was interrupted drawing, the line only reaches the serial port if there is one
+	let Some(_printing) = percpu::start_printing(Console::Framebuffer) else { return };
+	WRITER.get().unwrap().lock().write_fmt(args).unwrap();
 }
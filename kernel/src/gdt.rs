@@ -1,4 +1,7 @@
+use alloc::{boxed::Box, vec};
+use core::cell::UnsafeCell;
 use core::ptr::addr_of;
+use core::sync::atomic::Ordering;
 
 use x86_64::instructions::tables::load_tss;
 use x86_64::registers::segmentation::{Segment, CS, SS};
@@ -6,41 +9,130 @@ use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
-use crate::once_lock::OnceLock;
+use crate::percpu::{self, PerCpu};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-pub static GDT: OnceLock<(GlobalDescriptorTable, Selectors)> = OnceLock::new();
-pub static TSS: OnceLock<TaskStateSegment> = OnceLock::new();
+pub const MACHINE_CHECK_IST_INDEX: u16 = 1;
+/// NMIs may hit the kernel while it still runs on the user stack, right after `syscall` or
+/// before `sysret`
+pub const NMI_IST_INDEX: u16 = 2;
+const IST_STACK_SIZE: usize = 4096 * 5;
 
 #[derive(Debug)]
 pub struct Selectors {
 	code_selector: SegmentSelector,
-	tss_selector: SegmentSelector,
 	data_selector: SegmentSelector,
+	user_data_selector: SegmentSelector,
+	user_code_selector: SegmentSelector,
+	tss_selector: SegmentSelector,
+}
+
+impl Selectors {
+	pub fn kernel_code(&self) -> SegmentSelector { self.code_selector }
+
+	pub fn kernel_data(&self) -> SegmentSelector { self.data_selector }
+
+	/// Code segment of ring 3, its RPL is already 3
+	pub fn user_code(&self) -> SegmentSelector { self.user_code_selector }
+
+	/// Data and stack segment of ring 3, its RPL is already 3
+	pub fn user_data(&self) -> SegmentSelector { self.user_data_selector }
 }
 
+/// The TSS of a core. Its `privilege_stack_table[0]` follows the running thread, the CPU loads
+/// it as the stack pointer on every interrupt taken in ring 3
+pub struct Tss(UnsafeCell<TaskStateSegment>);
+
+impl Tss {
+	pub fn new(tss: TaskStateSegment) -> Tss { Tss(UnsafeCell::new(tss)) }
+}
+
+impl core::fmt::Debug for Tss {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		let rsp0 = unsafe { (*self.0.get()).privilege_stack_table[0] };
+		f.debug_struct("Tss").field("rsp0", &rsp0).finish()
+	}
+}
+
+// Only written by its own core, with interrupts disabled
+unsafe impl Sync for Tss {}
+
 pub fn init_tss() -> TaskStateSegment {
 	let mut tss = TaskStateSegment::new();
 	tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-		const STACK_SIZE: usize = 4096 * 5;
-		static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+		static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
 
 		let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
-		stack_start + STACK_SIZE.try_into().unwrap()
+		stack_start + IST_STACK_SIZE.try_into().unwrap()
 	};
+	tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = {
+		static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+		let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
+		stack_start + IST_STACK_SIZE.try_into().unwrap()
+	};
+	tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+		static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+		let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
+		stack_start + IST_STACK_SIZE.try_into().unwrap()
+	};
+	tss
+}
+
+/// Same as [`init_tss`] but for application processors, whose stacks come from the heap
+pub fn init_ap_tss() -> TaskStateSegment {
+	let mut tss = TaskStateSegment::new();
+	for index in [DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX] {
+		let stack = Box::leak(vec![0u8; IST_STACK_SIZE].into_boxed_slice());
+		tss.interrupt_stack_table[index as usize] =
+			VirtAddr::from_ptr(stack.as_ptr()) + IST_STACK_SIZE.try_into().unwrap();
+	}
 	tss
 }
 
-pub fn init_gdt() -> (GlobalDescriptorTable, Selectors) {
+/// Kernel segments come first, then the user ones with data before code, the layout `syscall`
+/// and `sysret` derive their selectors from
+pub fn init_gdt(tss: &'static Tss) -> (GlobalDescriptorTable, Selectors) {
 	let mut gdt = GlobalDescriptorTable::new();
 	let code_selector = gdt.append(Descriptor::kernel_code_segment());
-	let tss_selector = gdt.append(Descriptor::tss_segment(TSS.get().unwrap()));
 	let data_selector = gdt.append(Descriptor::kernel_data_segment());
-	(gdt, Selectors { code_selector, tss_selector, data_selector })
+	let user_data_selector = gdt.append(Descriptor::user_data_segment());
+	let user_code_selector = gdt.append(Descriptor::user_code_segment());
+	// The TSS stays writable behind the descriptor, see `set_kernel_stack`
+	let tss_selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(tss.0.get()) });
+	(gdt, Selectors {
+		code_selector,
+		data_selector,
+		user_data_selector,
+		user_code_selector,
+		tss_selector,
+	})
+}
+
+/// Stores `tss` and a GDT around it in the per-CPU area of `cpu` and loads both. Every core
+/// needs its own TSS, as loading one marks its descriptor busy
+pub fn init_cpu(cpu: &'static PerCpu, tss: TaskStateSegment) {
+	cpu.tss.set(Tss::new(tss)).expect("TSS initialised twice");
+	cpu.gdt.set(init_gdt(cpu.tss.get().unwrap())).expect("GDT initialised twice");
+	load_gdt(cpu.gdt.get().unwrap());
+}
+
+/// Selectors of the GDT of the calling core
+pub fn selectors() -> &'static Selectors {
+	&percpu::current().gdt.get().expect("GDT not initialised").1
+}
+
+/// Makes `top` the stack the calling core switches to when an interrupt or a system call
+/// arrives in ring 3. Must be called with interrupts disabled
+pub fn set_kernel_stack(top: VirtAddr) {
+	let cpu = percpu::current();
+	let tss = cpu.tss.get().expect("TSS not initialised");
+	unsafe { (*tss.0.get()).privilege_stack_table[0] = top };
+	cpu.kernel_stack.store(top.as_u64() as usize, Ordering::Relaxed);
 }
 
-pub fn load_gdt() {
-	let gdt = GDT.get().unwrap();
+pub fn load_gdt(gdt: &'static (GlobalDescriptorTable, Selectors)) {
 	gdt.0.load();
 	unsafe {
 		CS::set_reg(gdt.1.code_selector);
@@ -1,102 +1,317 @@
-use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+use core::{
+	arch::asm,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
 use pic8259::ChainedPics;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{
+	instructions::port::Port,
+	structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+	PrivilegeLevel, VirtAddr,
+};
 
-use crate::{mutex::Mutex, once_lock::OnceLock, print, println};
+use crate::{
+	mutex::{IrqMutex, LockStats},
+	once_lock::Lazy,
+	percpu, print, println, smp,
+};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static KEYBOARD: OnceLock<Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>>> = OnceLock::new();
+pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(init_idt);
+pub static PICS: IrqMutex<ChainedPics> =
+	IrqMutex::with_stats(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) }, &PICS_STATS);
+static PICS_STATS: LockStats = LockStats::new("PICS");
 
-pub static IDT: OnceLock<InterruptDescriptorTable> = OnceLock::new();
-pub static PICS: Mutex<ChainedPics> =
-	Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+/// OCW3 command that makes the next read of the command port return the In-Service Register
+const PIC_READ_ISR: u8 = 0x0B;
+/// OCW2 non-specific end of interrupt
+const PIC_EOI: u8 = 0x20;
 
 #[derive(Debug)]
 #[repr(u8)]
 pub enum InterruptIndex {
-	Timer = PIC_1_OFFSET,
+	Timer          = PIC_1_OFFSET,
 	Keyboard,
+	/// IRQ 7, raised by the master PIC when an interrupt disappears before it is acknowledged
+	SpuriousMaster = PIC_1_OFFSET + 7,
+	/// IRQ 9, where PC chipsets route the ACPI SCI, see [`crate::power::init_sci`]
+	AcpiSci        = PIC_1_OFFSET + 9,
+	/// IRQ 15, the slave PIC equivalent of [`InterruptIndex::SpuriousMaster`]
+	SpuriousSlave  = PIC_2_OFFSET + 7,
+	/// `int 0x80`, the slow way into [`crate::syscall`], open to ring 3
+	SystemCall     = 0x80,
+	/// IPI asking the core to run the pending [`crate::ipi::call`]
+	CallFunction   = 0xF0,
+	/// IPI that only wakes a halted core
+	Wakeup         = 0xF1,
+	/// IPI asking the core to check its run queue, see [`crate::thread::preempt`]
+	Reschedule     = 0xF2,
+	/// IPI asking the core to look for corrected errors, see [`crate::mce::poll_all`]
+	McePoll        = 0xF3,
+	/// APIC timer of the AP watching the boot processor, see [`crate::watchdog::check_boot_cpu`]
+	WatchdogTimer  = 0xF4,
+	/// Spurious vector programmed into the local APIC, its low nibble must be all ones
+	ApicSpurious   = 0xFF,
 }
 
 impl InterruptIndex {
 	fn into_u8(self) -> u8 { self as u8 }
 }
 
-pub fn init_kbd() -> Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> {
-	// let mut desc = x86_64::instructions::port::Port::new(0x64);
-	// let mut val: u8 = 0b0;
+/// Number of times each vector has fired on a core, plus the spurious interrupts that were
+/// ignored. Every core keeps its own in its per-CPU area
+#[derive(Debug)]
+pub struct IrqStats {
+	counts: [AtomicU64; 256],
+	spurious: AtomicU64,
+}
+
+impl IrqStats {
+	pub const fn new() -> IrqStats {
+		IrqStats { counts: [const { AtomicU64::new(0) }; 256], spurious: AtomicU64::new(0) }
+	}
+
+	pub fn record(&self, vector: u8) {
+		self.counts[vector as usize].fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn record_spurious(&self) { self.spurious.fetch_add(1, Ordering::Relaxed); }
+
+	pub fn count(&self, vector: u8) -> u64 { self.counts[vector as usize].load(Ordering::Relaxed) }
+
+	pub fn spurious(&self) -> u64 { self.spurious.load(Ordering::Relaxed) }
+}
+
+impl Default for IrqStats {
+	fn default() -> Self { Self::new() }
+}
+
+/// Human readable name of an interrupt vector, used by [`dump_stats`]
+pub fn vector_name(vector: u8) -> &'static str {
+	match vector {
+		0 => "Divide Error",
+		1 => "Debug",
+		2 => "NMI",
+		3 => "Breakpoint",
+		4 => "Overflow",
+		5 => "Bound Range",
+		6 => "Invalid Opcode",
+		7 => "Device Not Available",
+		8 => "Double Fault",
+		10 => "Invalid TSS",
+		11 => "Segment Not Present",
+		12 => "Stack Segment Fault",
+		13 => "General Protection",
+		14 => "Page Fault",
+		16 => "x87 Floating Point",
+		17 => "Alignment Check",
+		18 => "Machine Check",
+		19 => "SIMD Floating Point",
+		v if v == InterruptIndex::Timer as u8 => "Timer",
+		v if v == InterruptIndex::Keyboard as u8 => "Keyboard",
+		v if v == InterruptIndex::SpuriousMaster as u8 => "PIC Spurious (IRQ 7)",
+		v if v == InterruptIndex::AcpiSci as u8 => "ACPI SCI",
+		v if v == InterruptIndex::SpuriousSlave as u8 => "PIC Spurious (IRQ 15)",
+		v if v == InterruptIndex::SystemCall as u8 => "System Call",
+		v if v == InterruptIndex::CallFunction as u8 => "IPI Function Call",
+		v if v == InterruptIndex::Wakeup as u8 => "IPI Wakeup",
+		v if v == InterruptIndex::Reschedule as u8 => "IPI Reschedule",
+		v if v == InterruptIndex::McePoll as u8 => "IPI MCE Poll",
+		v if v == InterruptIndex::WatchdogTimer as u8 => "APIC Timer (Watchdog)",
+		v if v == InterruptIndex::ApicSpurious as u8 => "APIC Spurious",
+		PIC_1_OFFSET..=255 => "IRQ",
+		_ => "Reserved",
+	}
+}
+
+fn record(vector: u8) { percpu::current().irq_stats.record(vector); }
+
+fn record_spurious() { percpu::current().irq_stats.record_spurious(); }
+
+/// Prints every vector that fired at least once with a column per online core, in the spirit
+/// of `/proc/interrupts`
+pub fn dump_stats() {
+	let cpus = || (0..smp::MAX_CPUS).filter(|&cpu| smp::is_online(cpu)).map(percpu::get);
+
+	print!("{:>4}", "VEC");
+	for cpu in cpus() {
+		print!(" {:>9}{:<2}", "CPU", cpu.id());
+	}
+	println!("  NAME");
+
+	for vector in 0..=255u8 {
+		if cpus().all(|cpu| cpu.irq_stats.count(vector) == 0) {
+			continue;
+		}
+
+		print!("{:>4}", vector);
+		for cpu in cpus() {
+			print!(" {:>11}", cpu.irq_stats.count(vector));
+		}
+		println!("  {}", vector_name(vector));
+	}
+
+	print!("{:>4}", "SPU");
+	for cpu in cpus() {
+		print!(" {:>11}", cpu.irq_stats.spurious());
+	}
+	println!("  Ignored spurious");
+}
+
+/// Acknowledges an IRQ of the master PIC without taking [`PICS`], for handlers that must not
+/// lock. Everything else using the PICs does so with interrupts disabled on this core
+fn master_eoi() { unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) }; }
+
+/// Reads the In-Service Register of the PIC whose command port is `command`
+fn pic_isr(command: u16) -> u8 {
+	let mut port = Port::<u8>::new(command);
+	unsafe {
+		port.write(PIC_READ_ISR);
+		port.read()
+	}
+}
+
+/// Frame pointer of the code the current exception interrupted, pushed by the prologue of its
+/// handler. Only meaningful inlined into an `extern "x86-interrupt"` function
+#[inline(always)]
+fn interrupted_rbp() -> u64 {
+	let rbp: u64;
+	unsafe { asm!("mov {}, [rbp]", out(reg) rbp, options(nostack, preserves_flags, readonly)) };
+	rbp
+}
 
-	// println!("Waiting on IO Port 0x64..");
-	// while val & 0b1 != 0b1 {
-	// 	val = unsafe { desc.read() };
-	// 	core::hint::spin_loop();
-	// }
+fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+	stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
 
-	Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore))
+/// Ends the thread whose ring 3 code raised exception `vector`, the kernel itself carries on
+fn kill_user(vector: u8, stack_frame: &InterruptStackFrame) -> ! {
+	println!(
+		"{} in user mode at {:?}, ending thread {}",
+		vector_name(vector),
+		stack_frame.instruction_pointer,
+		crate::thread::current().as_usize()
+	);
+	crate::thread::exit()
 }
 
-pub fn init_idt() -> InterruptDescriptorTable {
+fn init_idt() -> InterruptDescriptorTable {
 	let mut idt = InterruptDescriptorTable::new();
 	idt.breakpoint.set_handler_fn(breakpoint_handler);
 	unsafe {
+		idt.non_maskable_interrupt
+			.set_handler_fn(nmi_handler)
+			.set_stack_index(crate::gdt::NMI_IST_INDEX);
 		idt.double_fault
 			.set_handler_fn(double_fault_handler)
 			.set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+		// Typed as diverging by `x86_64`, but the handler returns after corrected errors
+		idt.machine_check
+			.set_handler_addr(VirtAddr::new(machine_check_handler as *const () as u64))
+			.set_stack_index(crate::gdt::MACHINE_CHECK_IST_INDEX);
 	}
 	idt[InterruptIndex::Timer.into_u8()].set_handler_fn(timer_interrupt_handler);
 	idt[InterruptIndex::Keyboard.into_u8()].set_handler_fn(keyboard_interrupt_handler);
+	idt[InterruptIndex::AcpiSci.into_u8()].set_handler_fn(acpi_sci_handler);
+	idt[InterruptIndex::SpuriousMaster.into_u8()].set_handler_fn(spurious_master_handler);
+	idt[InterruptIndex::SpuriousSlave.into_u8()].set_handler_fn(spurious_slave_handler);
+	idt[InterruptIndex::ApicSpurious.into_u8()].set_handler_fn(apic_spurious_handler);
+	idt[InterruptIndex::CallFunction.into_u8()].set_handler_fn(call_function_handler);
+	idt[InterruptIndex::Wakeup.into_u8()].set_handler_fn(wakeup_handler);
+	idt[InterruptIndex::Reschedule.into_u8()].set_handler_fn(reschedule_handler);
+	idt[InterruptIndex::McePoll.into_u8()].set_handler_fn(mce_poll_handler);
+	idt[InterruptIndex::WatchdogTimer.into_u8()].set_handler_fn(watchdog_timer_handler);
+	unsafe {
+		idt[InterruptIndex::SystemCall.into_u8()]
+			.set_handler_addr(crate::syscall::int80_entry())
+			.set_privilege_level(PrivilegeLevel::Ring3);
+	}
 	idt.page_fault.set_handler_fn(page_fault_handler);
+	idt.divide_error.set_handler_fn(divide_error_handler);
+	idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+	idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
 	idt
 }
 
 pub fn init_pics() { unsafe { PICS.lock().initialize() }; }
 
-pub fn load_idt() { IDT.get().unwrap().load(); }
+pub fn load_idt() { IDT.load(); }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	record(3);
 	println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn nmi_handler(_stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter_paranoid();
+	record(2);
+	crate::watchdog::handle_nmi();
+}
+
 extern "x86-interrupt" fn double_fault_handler(
 	stack_frame: InterruptStackFrame,
 	_error_code: u64,
 ) -> ! {
+	let _gs = percpu::KernelGs::enter_paranoid();
+	record(8);
+	// The backtrace of the panic handler stops at this handler, start from the faulting code
+	crate::backtrace::print_from_frame(stack_frame.instruction_pointer, interrupted_rbp());
 	panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter_paranoid();
+	record(18);
+	crate::mce::handle(&stack_frame)
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	let irq = percpu::enter_irq();
+	record(InterruptIndex::Timer.into_u8());
+	crate::time::tick();
+	crate::watchdog::heartbeat();
+	if crate::time::ticks() % (crate::mce::POLL_INTERVAL_MS * crate::time::TIMER_HZ / 1000) == 0 {
+		crate::mce::poll_all();
+	}
+	if crate::time::ticks() % (crate::watchdog::CHECK_INTERVAL_MS * crate::time::TIMER_HZ / 1000)
+		== 0
+	{
+		crate::watchdog::check();
+	}
+	crate::thread::tick();
 	// print!(".");
 
 	unsafe {
 		PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.into_u8());
 	}
-}
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-	use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-	use x86_64::instructions::port::Port;
+	// The thread switched to is not inside this handler, the core must not count as being in one
+	drop(irq);
+	crate::thread::preempt();
+}
 
-	let mut keyboard = KEYBOARD.get().unwrap().lock();
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	let _irq = percpu::enter_irq();
+	record(InterruptIndex::Keyboard.into_u8());
+	crate::keyboard::handle_irq();
+	master_eoi();
+}
 
-	let mut port = Port::new(0x60);
-	let scancode: u8 = unsafe { port.read() };
-	if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-		if let Some(key) = keyboard.process_keyevent(key_event) {
-			// print!("{:0x?}", key);
-			match key {
-				DecodedKey::Unicode(character) => print!("{}", character),
-				#[cfg(feature = "serial")]
-				DecodedKey::RawKey(key) => crate::serial_println!("Raw: {:?}", key),
-				#[cfg(not(feature = "serial"))]
-				DecodedKey::RawKey(_) => (),
-			}
-		}
-	}
+extern "x86-interrupt" fn acpi_sci_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	let _irq = percpu::enter_irq();
+	record(InterruptIndex::AcpiSci.into_u8());
+	crate::power::handle_sci();
 
 	unsafe {
-		PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.into_u8());
+		PICS.lock().notify_end_of_interrupt(InterruptIndex::AcpiSci.into_u8());
 	}
 }
 
@@ -106,9 +321,129 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
 	use x86_64::registers::control::Cr2;
 
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	record(14);
+	if from_user(&stack_frame) {
+		println!("Accessed Address: {:?}, Error Code: {:?}", Cr2::read(), error_code);
+		kill_user(14, &stack_frame);
+	}
 	println!("EXCEPTION: PAGE FAULT");
 	println!("Accessed Address: {:?}", Cr2::read());
 	println!("Error Code: {:?}", error_code);
 	println!("{:#?}", stack_frame);
+	crate::backtrace::print_from_frame(stack_frame.instruction_pointer, interrupted_rbp());
 	crate::hlt_loop();
 }
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	record(0);
+	if from_user(&stack_frame) {
+		kill_user(0, &stack_frame);
+	}
+	crate::backtrace::print_from_frame(stack_frame.instruction_pointer, interrupted_rbp());
+	panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	record(6);
+	if from_user(&stack_frame) {
+		kill_user(6, &stack_frame);
+	}
+	crate::backtrace::print_from_frame(stack_frame.instruction_pointer, interrupted_rbp());
+	panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+	stack_frame: InterruptStackFrame,
+	error_code: u64,
+) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	record(13);
+	if from_user(&stack_frame) {
+		kill_user(13, &stack_frame);
+	}
+	crate::backtrace::print_from_frame(stack_frame.instruction_pointer, interrupted_rbp());
+	panic!("EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}", error_code, stack_frame);
+}
+
+/// IRQ 7 is only real if the master PIC reports it in service, otherwise no EOI must be sent
+extern "x86-interrupt" fn spurious_master_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	if pic_isr(PIC_1_COMMAND) & (1 << 7) == 0 {
+		record_spurious();
+		return;
+	}
+
+	record(InterruptIndex::SpuriousMaster.into_u8());
+	unsafe {
+		PICS.lock().notify_end_of_interrupt(InterruptIndex::SpuriousMaster.into_u8());
+	}
+}
+
+/// A spurious IRQ 15 still went through the cascade line, so the master expects its EOI
+extern "x86-interrupt" fn spurious_slave_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	if pic_isr(PIC_2_COMMAND) & (1 << 7) == 0 {
+		record_spurious();
+		unsafe {
+			// Any master vector only acknowledges the master PIC, use the cascade one
+			PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + 2);
+		}
+		return;
+	}
+
+	record(InterruptIndex::SpuriousSlave.into_u8());
+	unsafe {
+		PICS.lock().notify_end_of_interrupt(InterruptIndex::SpuriousSlave.into_u8());
+	}
+}
+
+/// The local APIC never expects an EOI for its spurious vector
+extern "x86-interrupt" fn apic_spurious_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	record_spurious();
+}
+
+extern "x86-interrupt" fn call_function_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	let _irq = percpu::enter_irq();
+	record(InterruptIndex::CallFunction.into_u8());
+	crate::ipi::handle_pending(percpu::current().id());
+	crate::apic::LAPIC.get().unwrap().eoi();
+}
+
+extern "x86-interrupt" fn wakeup_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	record(InterruptIndex::Wakeup.into_u8());
+	crate::watchdog::heartbeat();
+	crate::apic::LAPIC.get().unwrap().eoi();
+}
+
+extern "x86-interrupt" fn reschedule_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	let irq = percpu::enter_irq();
+	record(InterruptIndex::Reschedule.into_u8());
+	crate::apic::LAPIC.get().unwrap().eoi();
+
+	drop(irq);
+	crate::thread::preempt();
+}
+
+extern "x86-interrupt" fn mce_poll_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	let _irq = percpu::enter_irq();
+	record(InterruptIndex::McePoll.into_u8());
+	crate::mce::poll();
+	crate::apic::LAPIC.get().unwrap().eoi();
+}
+
+extern "x86-interrupt" fn watchdog_timer_handler(stack_frame: InterruptStackFrame) {
+	let _gs = percpu::KernelGs::enter(&stack_frame);
+	let _irq = percpu::enter_irq();
+	record(InterruptIndex::WatchdogTimer.into_u8());
+	crate::watchdog::heartbeat();
+	crate::watchdog::check_boot_cpu();
+	crate::apic::LAPIC.get().unwrap().eoi();
+}
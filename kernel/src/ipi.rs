@@ -0,0 +1,178 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::{
+	instructions::tlb,
+	structures::paging::{Page, Size4KiB},
+	VirtAddr,
+};
+
+use crate::{apic, interrupts::InterruptIndex, percpu, smp};
+
+/// Above this many pages a shootdown flushes the whole TLB instead of page by page
+const SHOOTDOWN_FULL_FLUSH: usize = 32;
+
+/// Set while a remote call is in flight, only one can be at a time
+static CALL_BUSY: AtomicBool = AtomicBool::new(false);
+static CALL: CallRequest = CallRequest::new();
+/// Cores that still have to run [`CALL`]
+static PENDING: [AtomicBool; smp::MAX_CPUS] = [const { AtomicBool::new(false) }; smp::MAX_CPUS];
+
+// [`call`] gathers its targets in a `u64` mask
+const _: () = assert!(smp::MAX_CPUS <= u64::BITS as usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+	Cpu(usize),
+	All,
+	AllButSelf,
+}
+
+impl Target {
+	fn includes(self, cpu: usize, me: usize) -> bool {
+		match self {
+			Target::Cpu(target) => target == cpu,
+			Target::All => true,
+			Target::AllButSelf => cpu != me,
+		}
+	}
+}
+
+#[derive(Debug)]
+struct CallRequest {
+	func: AtomicUsize,
+	arg: AtomicUsize,
+	remaining: AtomicUsize,
+}
+
+impl CallRequest {
+	const fn new() -> CallRequest {
+		CallRequest {
+			func: AtomicUsize::new(0),
+			arg: AtomicUsize::new(0),
+			remaining: AtomicUsize::new(0),
+		}
+	}
+}
+
+/// Pages a shootdown has to invalidate, lives on the initiator's stack until every core is done
+#[derive(Debug)]
+struct FlushRange {
+	start: VirtAddr,
+	pages: usize,
+}
+
+/// Sends `vector` to `target`. Does nothing before the local APIC is initialised, when the BSP
+/// is the only core running
+pub fn send(target: Target, vector: u8) {
+	const SHORTHAND_ALL: u32 = 0b10 << 18;
+	const SHORTHAND_ALL_BUT_SELF: u32 = 0b11 << 18;
+	/// Fixed delivery mode with the level asserted
+	const FIXED: u32 = 1 << 14;
+
+	let Some(lapic) = apic::LAPIC.get() else { return };
+	let vector = vector as u32;
+	match target {
+		Target::Cpu(cpu) => lapic.send_ipi(percpu::get(cpu).apic_id(), FIXED | vector),
+		Target::All => lapic.send_ipi(0, SHORTHAND_ALL | FIXED | vector),
+		Target::AllButSelf => lapic.send_ipi(0, SHORTHAND_ALL_BUT_SELF | FIXED | vector),
+	}
+}
+
+/// Sends a non-maskable interrupt to `cpu`, which gets through even with interrupts disabled
+pub fn send_nmi(cpu: usize) {
+	/// NMI delivery mode, the vector field is ignored
+	const NMI: u32 = (0b100 << 8) | (1 << 14);
+
+	let Some(lapic) = apic::LAPIC.get() else { return };
+	lapic.send_ipi(percpu::get(cpu).apic_id(), NMI);
+}
+
+/// Runs `func(arg)` on every online core in `target` and waits until all of them returned.
+/// The calling core runs its part directly
+pub fn call(target: Target, func: fn(usize), arg: usize) {
+	let me = percpu::current().id();
+
+	// Keep serving calls aimed at us while another core owns the mailbox, it may be waiting
+	// for this very core
+	while CALL_BUSY
+		.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+		.is_err()
+	{
+		handle_pending(me);
+		core::hint::spin_loop();
+	}
+
+	// Snapshot the targets once, a core coming online in between must neither be waited for
+	// without being sent the call nor be sent one it is not counted for
+	let remote = (0..smp::MAX_CPUS)
+		.filter(|&cpu| cpu != me && target.includes(cpu, me) && smp::is_online(cpu))
+		.fold(0u64, |mask, cpu| mask | 1 << cpu);
+
+	CALL.func.store(func as usize, Ordering::Relaxed);
+	CALL.arg.store(arg, Ordering::Relaxed);
+	CALL.remaining.store(remote.count_ones() as usize, Ordering::Relaxed);
+	for cpu in cpus(remote) {
+		PENDING[cpu].store(true, Ordering::Release);
+		send(Target::Cpu(cpu), InterruptIndex::CallFunction as u8);
+	}
+
+	if target.includes(me, me) {
+		func(arg);
+	}
+
+	while CALL.remaining.load(Ordering::Acquire) > 0 {
+		// A core that went offline after the snapshot may never take the IPI. Whoever clears
+		// its `PENDING` first, the core or this loop, accounts for it
+		for cpu in cpus(remote).filter(|&cpu| !smp::is_online(cpu)) {
+			if PENDING[cpu].swap(false, Ordering::Acquire) {
+				CALL.remaining.fetch_sub(1, Ordering::Release);
+			}
+		}
+		core::hint::spin_loop();
+	}
+	CALL_BUSY.store(false, Ordering::Release);
+}
+
+/// The cores whose bit is set in `mask`
+fn cpus(mut mask: u64) -> impl Iterator<Item = usize> {
+	core::iter::from_fn(move || {
+		if mask == 0 {
+			return None;
+		}
+		let cpu = mask.trailing_zeros() as usize;
+		mask &= mask - 1;
+		Some(cpu)
+	})
+}
+
+/// Runs the remote call addressed to `cpu`, if any
+pub fn handle_pending(cpu: usize) {
+	if !PENDING[cpu].swap(false, Ordering::Acquire) {
+		return;
+	}
+
+	let func = CALL.func.load(Ordering::Relaxed);
+	let func = unsafe { core::mem::transmute::<usize, fn(usize)>(func) };
+	func(CALL.arg.load(Ordering::Relaxed));
+	CALL.remaining.fetch_sub(1, Ordering::Release);
+}
+
+/// Invalidates `pages` pages starting at `start` on every other core. The caller flushes its
+/// own TLB, usually through the `MapperFlush` returned by the mapper
+pub fn shootdown(start: VirtAddr, pages: usize) {
+	fn flush(arg: usize) {
+		let range = unsafe { &*(arg as *const FlushRange) };
+		if range.pages > SHOOTDOWN_FULL_FLUSH {
+			tlb::flush_all();
+			return;
+		}
+
+		let first = Page::<Size4KiB>::containing_address(range.start);
+		for page in Page::range(first, first + range.pages as u64) {
+			tlb::flush(page.start_address());
+		}
+	}
+
+	let range = FlushRange { start, pages };
+	call(Target::AllButSelf, flush, &range as *const FlushRange as usize);
+}
@@ -0,0 +1,98 @@
+use core::{
+	future::Future,
+	sync::atomic::{AtomicBool, Ordering},
+	task::{Context, Poll},
+};
+
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use x86_64::instructions::port::Port;
+
+use crate::{atomic_waker::AtomicWaker, print, ring::SpscRing};
+
+const DATA_PORT: u16 = 0x60;
+/// Scancodes the IRQ can queue before the consumer falls behind and new ones are dropped
+const QUEUE_SIZE: usize = 128;
+
+/// Raw scancodes pushed by the IRQ handler, waiting to be decoded
+static SCANCODES: SpscRing<u8, QUEUE_SIZE> = SpscRing::new();
+
+/// Task waiting on [`SCANCODES`]
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// Set while a [`ScancodeStream`] exists
+static STREAM_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Body of the keyboard IRQ handler. Only queues the scancode and wakes the task reading them,
+/// it takes no lock
+pub fn handle_irq() {
+	let scancode = unsafe { Port::<u8>::new(DATA_PORT).read() };
+	// Only the boot processor takes the IRQ, and its handler does not nest
+	let _ = unsafe { SCANCODES.push(scancode) };
+	WAKER.wake();
+}
+
+/// The queued scancodes as an async stream. At most one exists at a time, it is the consumer of
+/// the queue
+#[derive(Debug)]
+pub struct ScancodeStream {
+	_private: (),
+}
+
+impl ScancodeStream {
+	/// Panics if another stream exists
+	pub fn new() -> ScancodeStream {
+		let taken = STREAM_TAKEN.swap(true, Ordering::Acquire);
+		assert!(!taken, "there is already a ScancodeStream");
+		ScancodeStream { _private: () }
+	}
+
+	pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<u8> {
+		if let Some(scancode) = unsafe { SCANCODES.pop() } {
+			return Poll::Ready(scancode);
+		}
+
+		// A scancode queued before the waker was registered did not wake anyone, so look again
+		WAKER.register(cx.waker());
+		match unsafe { SCANCODES.pop() } {
+			Some(scancode) => Poll::Ready(scancode),
+			None => Poll::Pending,
+		}
+	}
+
+	/// Waits for the next scancode
+	pub fn next(&mut self) -> impl Future<Output = u8> + '_ {
+		core::future::poll_fn(|cx| self.poll_next(cx))
+	}
+}
+
+impl Default for ScancodeStream {
+	fn default() -> Self { Self::new() }
+}
+
+impl Drop for ScancodeStream {
+	fn drop(&mut self) { STREAM_TAKEN.store(false, Ordering::Release); }
+}
+
+/// Task decoding the scancodes and echoing the keys
+pub async fn print_keypresses() {
+	let mut scancodes = ScancodeStream::new();
+	let mut keyboard = Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore);
+
+	loop {
+		let scancode = scancodes.next().await;
+		let Ok(Some(event)) = keyboard.add_byte(scancode) else { continue };
+		match keyboard.process_keyevent(event) {
+			Some(DecodedKey::Unicode(character)) => print!("{}", character),
+			#[cfg(feature = "serial")]
+			Some(DecodedKey::RawKey(key)) => crate::serial_println!("Raw: {:?}", key),
+			#[cfg(not(feature = "serial"))]
+			Some(DecodedKey::RawKey(_)) => (),
+			None => (),
+		}
+	}
+}
+
+/// Scancodes received since boot
+pub fn received() -> u64 { SCANCODES.pushed() + SCANCODES.dropped() }
+
+/// Scancodes lost because the queue was full
+pub fn dropped() -> u64 { SCANCODES.dropped() }
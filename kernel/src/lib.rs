@@ -6,48 +6,90 @@ use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
 
 extern crate alloc;
 
+pub mod acpi_tables;
 pub mod allocator;
 pub mod apic;
+pub mod atomic_waker;
+pub mod backtrace;
+pub mod blocking;
+pub mod cpu;
+pub mod executor;
+pub mod fpu;
 pub mod frame;
 pub mod gdt;
 pub mod interrupts;
+pub mod ipi;
+pub mod keyboard;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod mce;
 pub mod mem;
 pub mod mutex;
 pub mod once_lock;
+pub mod percpu;
+pub mod power;
+pub mod ring;
 #[cfg(feature = "serial")]
 pub mod serial;
+pub mod smp;
+pub mod symbols;
+pub mod sync;
+pub mod syscall;
+pub mod thread;
+pub mod time;
+pub mod user;
 pub mod version;
+pub mod wait_queue;
+pub mod watchdog;
 
 pub fn init(
 	framebuffer: &'static mut bootloader_api::info::FrameBuffer,
+	rsdp_addr: Option<u64>,
 	mapper: &mut impl Mapper<Size4KiB>,
 	frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) {
+	// Locking records held locks in the per-CPU area, so it comes before the first `println`
+	let bsp = percpu::init(0);
 	let _ = frame::WRITER.set(frame::init_framebuffer(framebuffer));
 	#[cfg(feature = "serial")]
-	serial::SERIAL1.set(serial::serial_init()).expect("Single entry point");
+	once_lock::Lazy::force(&serial::SERIAL1);
 
 	println!("{}", version::VERSION);
 
-	println!("KEYBD...");
-	let Ok(_) = interrupts::KEYBOARD.set(interrupts::init_kbd()) else {
-		panic!("Failed interrupts::init_kbd")
-	};
+	cpu::print_report();
+
+	println!("FPU...");
+	fpu::init();
+	mce::init();
 
 	println!("GDT...");
-	gdt::TSS.set(gdt::init_tss()).unwrap();
-	gdt::GDT.set(gdt::init_gdt()).unwrap();
-	gdt::load_gdt();
+	gdt::init_cpu(bsp, gdt::init_tss());
+	syscall::init();
 
 	println!("Interrupts...");
-	interrupts::IDT.set(interrupts::init_idt()).unwrap();
 	interrupts::load_idt();
 	interrupts::init_pics();
+	time::init_pit();
 	x86_64::instructions::interrupts::enable();
 
+	// Taken before the heap so it is one of the low frames the SIPI can reach
+	let trampoline = smp::allocate_trampoline(frame_allocator);
+
 	println!("Heap...");
 	allocator::init_heap(mapper, frame_allocator).unwrap();
 	allocator::init_alloc();
+	thread::init();
+
+	println!("ACPI...");
+	if let Some(rsdp_addr) = rsdp_addr {
+		acpi_tables::init(rsdp_addr);
+	}
+	power::init_sci();
+
+	println!("SMP...");
+	smp::init(mapper, frame_allocator, trampoline);
+	println!("CPUs online: {}", smp::online_cpus());
+	watchdog::enable();
 	println!("Done!");
 }
 
@@ -0,0 +1,239 @@
+use core::{
+	fmt,
+	panic::Location,
+	sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+	frame,
+	once_lock::Lazy,
+	percpu::{self, PerCpu},
+	serial,
+};
+
+/// Lock classes tracked at most, one per lock address. Also the width of a [`DEPENDENCIES`] row
+const MAX_CLASSES: usize = 64;
+
+/// Cleared by the first report, the graph is not trusted after that and every later lock
+/// operation would only repeat it
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static CLASSES: [Class; MAX_CLASSES] = [const { Class::new() }; MAX_CLASSES];
+/// Bit `j` of row `i` is set once class `j` was taken while class `i` was held
+static DEPENDENCIES: [AtomicU64; MAX_CLASSES] = [const { AtomicU64::new(0) }; MAX_CLASSES];
+
+/// Every lock instance is its own class, the kernel's locks are nearly all statics anyway
+#[derive(Debug)]
+struct Class {
+	/// Address of the lock, zero for a free slot
+	lock: AtomicUsize,
+	/// `&'static Location` of the first acquisition
+	site: AtomicUsize,
+	/// First acquisition from an interrupt handler
+	in_irq_site: AtomicUsize,
+	/// First acquisition outside interrupt handlers with interrupts enabled
+	irqs_on_site: AtomicUsize,
+}
+
+impl Class {
+	const fn new() -> Class {
+		Class {
+			lock: AtomicUsize::new(0),
+			site: AtomicUsize::new(0),
+			in_irq_site: AtomicUsize::new(0),
+			irqs_on_site: AtomicUsize::new(0),
+		}
+	}
+
+	fn lock(&self) -> usize { self.lock.load(Ordering::Relaxed) }
+}
+
+/// Shows a recorded `&'static Location`, or `?` if it was never recorded
+struct Site(usize);
+
+impl fmt::Display for Site {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match unsafe { (self.0 as *const Location<'static>).as_ref() } {
+			Some(location) => write!(f, "{}", location),
+			None => write!(f, "?"),
+		}
+	}
+}
+
+fn site_of(location: &'static Location<'static>) -> usize {
+	location as *const Location<'static> as usize
+}
+
+/// Prints over serial, or on the framebuffer when this core is the one holding the serial port
+fn emit(args: fmt::Arguments) {
+	let held = &percpu::current().held_locks;
+	let free = |lock: usize| !held.contains(lock);
+
+	if Lazy::get(&serial::SERIAL1).is_some_and(|serial| free(serial as *const _ as usize)) {
+		serial::_print(args);
+	} else if frame::WRITER.get().is_some_and(|writer| free(writer as *const _ as usize)) {
+		frame::_print(args);
+	}
+}
+
+macro_rules! report {
+	($($arg:tt)*) => (emit(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// Returns the class of `lock`, registering it on first use
+fn class_of(lock: usize, location: &'static Location<'static>) -> Option<usize> {
+	for (index, class) in CLASSES.iter().enumerate() {
+		match class.lock.compare_exchange(0, lock, Ordering::AcqRel, Ordering::Acquire) {
+			Ok(_) => {
+				class.site.store(site_of(location), Ordering::Relaxed);
+				return Some(index);
+			}
+			Err(existing) if existing == lock => return Some(index),
+			Err(_) => {}
+		}
+	}
+
+	if ENABLED.swap(false, Ordering::Relaxed) {
+		report!("lockdep: more than {} lock classes, turning off", MAX_CLASSES);
+	}
+	None
+}
+
+/// Shortest chain of recorded dependencies leading from class `from` to class `to`, written
+/// into `chain`. Returns its length, zero if `to` cannot be reached
+fn find_chain(from: usize, to: usize, chain: &mut [usize; MAX_CLASSES]) -> usize {
+	let mut parent = [usize::MAX; MAX_CLASSES];
+	let mut queue = [0; MAX_CLASSES];
+	let (mut head, mut tail) = (0, 1);
+	queue[0] = from;
+	parent[from] = from;
+
+	while head < tail {
+		let class = queue[head];
+		head += 1;
+		if class == to {
+			let mut len = 0;
+			let mut at = to;
+			loop {
+				chain[len] = at;
+				len += 1;
+				if at == from {
+					break;
+				}
+				at = parent[at];
+			}
+			chain[..len].reverse();
+			return len;
+		}
+
+		let mut next = DEPENDENCIES[class].load(Ordering::Relaxed);
+		while next != 0 {
+			let dependency = next.trailing_zeros() as usize;
+			next &= next - 1;
+			if parent[dependency] == usize::MAX {
+				parent[dependency] = class;
+				queue[tail] = dependency;
+				tail += 1;
+			}
+		}
+	}
+	0
+}
+
+/// Validates taking `lock` at `location` on the calling core against every lock taken so far.
+/// Called by the lock types right before they start spinning, so a deadlock is reported instead
+/// of hanging the core
+pub fn acquire(lock: usize, location: &'static Location<'static>) {
+	validate(lock, location, false)
+}
+
+/// Like [`acquire`], for the read side of a reader-writer lock. Reading a lock the core already
+/// reads is not reported as recursion, only its writers exclude each other
+pub fn acquire_read(lock: usize, location: &'static Location<'static>) {
+	validate(lock, location, true)
+}
+
+/// Records that `lock` was taken at `location` without waiting for it. A try cannot deadlock, so
+/// it adds no ordering, but the lock counts as held for those taken after it
+pub fn try_acquired(lock: usize, location: &'static Location<'static>) {
+	if !ENABLED.load(Ordering::Relaxed) {
+		return;
+	}
+
+	let Some(class) = class_of(lock, location) else { return };
+	record_usage(percpu::current(), class, location);
+}
+
+fn validate(lock: usize, location: &'static Location<'static>, read: bool) {
+	if !ENABLED.load(Ordering::Relaxed) {
+		return;
+	}
+
+	let Some(class) = class_of(lock, location) else { return };
+	let cpu = percpu::current();
+
+	for (held, held_location) in cpu.held_locks.iter() {
+		let held_site = held_location.map_or(0, site_of);
+		if held == lock {
+			if read && cpu.held_locks.contains_shared(lock) {
+				continue;
+			}
+			ENABLED.store(false, Ordering::Relaxed);
+			report!("lockdep: recursive locking on CPU {}", cpu.id());
+			report!("  {:#018x} taken at {}", lock, Site(site_of(location)));
+			report!("  already held since {}", Site(held_site));
+			panic!("lockdep: recursive locking of {:#x} at {}", lock, location);
+		}
+
+		let Some(held_class) = class_of(held, held_location.unwrap_or(location)) else { return };
+		let bit = 1 << class;
+		if DEPENDENCIES[held_class].fetch_or(bit, Ordering::Relaxed) & bit != 0 {
+			continue;
+		}
+
+		// The new edge held -> lock closes a cycle if lock already leads back to held
+		let mut chain = [0; MAX_CLASSES];
+		let len = find_chain(class, held_class, &mut chain);
+		if len > 0 && ENABLED.swap(false, Ordering::Relaxed) {
+			report!("lockdep: possible deadlock, inconsistent lock order on CPU {}", cpu.id());
+			report!("  {:#018x} taken at {}", lock, Site(site_of(location)));
+			report!("  while holding {:#018x} taken at {}", held, Site(held_site));
+			report!("  but the opposite order was seen before:");
+			for &link in &chain[..len] {
+				let class = &CLASSES[link];
+				report!(
+					"    {:#018x} first taken at {}",
+					class.lock(),
+					Site(class.site.load(Ordering::Relaxed))
+				);
+			}
+			report!("    {:#018x}", lock);
+			return;
+		}
+	}
+
+	record_usage(cpu, class, location);
+}
+
+/// Remembers whether `class` was taken in an interrupt handler or with interrupts enabled, and
+/// reports it once it was seen both ways
+fn record_usage(cpu: &PerCpu, class: usize, location: &'static Location<'static>) {
+	let state = &CLASSES[class];
+	let lock = state.lock();
+	let usage = if cpu.in_interrupt() {
+		&state.in_irq_site
+	} else if x86_64::instructions::interrupts::are_enabled() {
+		&state.irqs_on_site
+	} else {
+		return;
+	};
+	let _ = usage.compare_exchange(0, site_of(location), Ordering::Relaxed, Ordering::Relaxed);
+
+	let in_irq = state.in_irq_site.load(Ordering::Relaxed);
+	let irqs_on = state.irqs_on_site.load(Ordering::Relaxed);
+	if in_irq != 0 && irqs_on != 0 && ENABLED.swap(false, Ordering::Relaxed) {
+		report!("lockdep: {:#018x} is taken in interrupts and with interrupts enabled", lock);
+		report!("  from an interrupt handler at {}", Site(in_irq));
+		report!("  with interrupts enabled at {}", Site(irqs_on));
+		report!("  the handler deadlocks if it interrupts that holder, use an IrqMutex");
+	}
+}
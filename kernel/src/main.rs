@@ -10,7 +10,7 @@ use x86_64::{structures::paging::Translate, VirtAddr};
 
 #[cfg(feature = "serial")]
 use kernel::serial_println;
-use kernel::{frame::WRITER, mem, println};
+use kernel::{executor::Executor, frame::WRITER, keyboard, mem, println, symbols};
 
 const CONFIG: bootloader_api::BootloaderConfig = {
 	let mut config = bootloader_api::BootloaderConfig::new_default();
@@ -21,7 +21,16 @@ const CONFIG: bootloader_api::BootloaderConfig = {
 bootloader_api::entry_point!(kernel_main, config = &CONFIG);
 
 fn kernel_main(
-	BootInfo { memory_regions, framebuffer, physical_memory_offset, .. }: &'static mut BootInfo,
+	BootInfo {
+		memory_regions,
+		framebuffer,
+		physical_memory_offset,
+		rsdp_addr,
+		ramdisk_addr,
+		ramdisk_len,
+		kernel_image_offset,
+		..
+	}: &'static mut BootInfo,
 ) -> ! {
 	let frameinfo = framebuffer.as_ref().unwrap().info();
 	let framebuffer = framebuffer.as_mut().unwrap();
@@ -29,7 +38,12 @@ fn kernel_main(
 	let mem_offset = physical_memory_offset.into_option().map(VirtAddr::new).unwrap();
 	let mut mapper = unsafe { mem::init(mem_offset) };
 	let mut frame_allocator = unsafe { mem::BootInfoFrameAllocator::init(memory_regions) };
-	kernel::init(framebuffer, &mut mapper, &mut frame_allocator);
+	if let Some(ramdisk) = ramdisk_addr.into_option() {
+		let ramdisk =
+			unsafe { core::slice::from_raw_parts(ramdisk as *const u8, *ramdisk_len as usize) };
+		symbols::init(ramdisk, *kernel_image_offset);
+	}
+	kernel::init(framebuffer, rsdp_addr.into_option(), &mut mapper, &mut frame_allocator);
 
 	let mut total_size = 0;
 	let mut regions = 0;
@@ -75,11 +89,21 @@ fn kernel_main(
 	#[cfg(feature = "serial")]
 	serial_println!("Hello World{}", "!");
 
-	kernel::hlt_loop()
+	let mut executor = Executor::new();
+	executor.spawn(keyboard::print_keypresses());
+	loop {
+		executor.run_ready_tasks();
+		kernel::power::handle_events();
+		kernel::watchdog::touch();
+		// Threads that are ready get the core before it halts
+		kernel::thread::yield_now();
+		executor.sleep_if_idle();
+	}
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
 	println!("{}", info);
+	kernel::backtrace::print();
 	kernel::hlt_loop()
 }
@@ -0,0 +1,190 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::{
+	registers::{
+		control::{Cr4, Cr4Flags},
+		model_specific::Msr,
+	},
+	structures::idt::InterruptStackFrame,
+};
+
+use crate::{cpu, interrupts::InterruptIndex, ipi, println};
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+const IA32_MC0_CTL: u32 = 0x400;
+/// `IA32_MCG_STATUS`: the interrupted code can be restarted at the address pushed
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+/// `IA32_MCG_STATUS`: a #MC is being handled, another one would shut the core down
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+/// Milliseconds between two polls of the banks for corrected errors
+pub const POLL_INTERVAL_MS: u64 = 5000;
+
+/// Number of banks reported by the boot processor, zero while MCA is disabled
+static BANKS: AtomicUsize = AtomicUsize::new(0);
+static CORRECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Decoded `IA32_MCi_STATUS` of one bank
+#[derive(Debug, Clone, Copy)]
+pub struct BankStatus {
+	pub bank: usize,
+	pub raw: u64,
+	pub addr: Option<u64>,
+	pub misc: Option<u64>,
+}
+
+impl BankStatus {
+	fn read(bank: usize) -> Option<BankStatus> {
+		let raw = unsafe { bank_msr(bank, 1).read() };
+		if raw & (1 << 63) == 0 {
+			return None;
+		}
+
+		let addr = (raw & (1 << 58) != 0).then(|| unsafe { bank_msr(bank, 2).read() });
+		let misc = (raw & (1 << 59) != 0).then(|| unsafe { bank_msr(bank, 3).read() });
+		Some(BankStatus { bank, raw, addr, misc })
+	}
+
+	/// Another error was lost because this one was not cleared in time
+	pub fn overflow(&self) -> bool { self.raw & (1 << 62) != 0 }
+
+	pub fn uncorrected(&self) -> bool { self.raw & (1 << 61) != 0 }
+
+	/// The processor context is corrupt, execution cannot safely continue
+	pub fn context_corrupt(&self) -> bool { self.raw & (1 << 57) != 0 }
+
+	pub fn mca_code(&self) -> u16 { self.raw as u16 }
+
+	pub fn model_code(&self) -> u16 { (self.raw >> 16) as u16 }
+
+	/// Coarse class of the architectural MCA error code
+	pub fn kind(&self) -> &'static str {
+		match self.mca_code() {
+			0 => "no error",
+			0x0001 => "unclassified",
+			0x0002 => "microcode ROM parity",
+			0x0003 => "external",
+			0x0004 => "FRC",
+			0x0005 => "internal parity",
+			0x0006 => "SMM handler code access violation",
+			0x0400..=0x07FF => "internal unclassified",
+			code if code & 0xEFFC == 0x000C => "generic cache hierarchy",
+			code if code & 0xEFF0 == 0x0010 => "TLB",
+			code if code & 0xEF80 == 0x0080 => "memory controller",
+			code if code & 0xEF00 == 0x0100 => "cache hierarchy",
+			code if code & 0xE800 == 0x0800 => "bus / interconnect",
+			_ => "unknown",
+		}
+	}
+
+	fn clear(&self) { unsafe { bank_msr(self.bank, 1).write(0) } }
+}
+
+impl core::fmt::Display for BankStatus {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(
+			f,
+			"MCE bank {}: {} {} error (MCA {:#06x}, model {:#06x}, status {:#018x})",
+			self.bank,
+			if self.uncorrected() { "uncorrected" } else { "corrected" },
+			self.kind(),
+			self.mca_code(),
+			self.model_code(),
+			self.raw
+		)?;
+		if let Some(addr) = self.addr {
+			write!(f, " addr {:#x}", addr)?;
+		}
+		if let Some(misc) = self.misc {
+			write!(f, " misc {:#x}", misc)?;
+		}
+		if self.overflow() {
+			write!(f, " [overflow]")?;
+		}
+		Ok(())
+	}
+}
+
+/// `register` is 0 for CTL, 1 for STATUS, 2 for ADDR and 3 for MISC
+fn bank_msr(bank: usize, register: u32) -> Msr {
+	Msr::new(IA32_MC0_CTL + 4 * bank as u32 + register)
+}
+
+/// Enables machine check exceptions and every error reporting bank on the calling core. Does
+/// nothing when CPUID lacks MCE or MCA
+pub fn init() {
+	let features = cpu::features();
+	if !features.mce || !features.mca {
+		return;
+	}
+
+	let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+	let banks = (cap & 0xFF) as usize;
+	unsafe {
+		if cap & (1 << 8) != 0 {
+			Msr::new(IA32_MCG_CTL).write(u64::MAX);
+		}
+		for bank in 0..banks {
+			bank_msr(bank, 0).write(u64::MAX);
+			bank_msr(bank, 1).write(0);
+		}
+		Cr4::update(|cr4| cr4.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION));
+	}
+	BANKS.store(banks, Ordering::Relaxed);
+}
+
+pub fn banks() -> usize { BANKS.load(Ordering::Relaxed) }
+
+/// Corrected errors logged by [`poll`] since boot
+pub fn corrected() -> u64 { CORRECTED.load(Ordering::Relaxed) }
+
+/// Logs and clears corrected errors of the calling core, which the processor records without
+/// raising #MC
+pub fn poll() {
+	for status in (0..banks()).filter_map(BankStatus::read) {
+		if status.uncorrected() {
+			continue;
+		}
+
+		CORRECTED.fetch_add(1, Ordering::Relaxed);
+		println!("{}", status);
+		status.clear();
+	}
+}
+
+/// Runs [`poll`] on every core, banks are only readable from the core they belong to. The
+/// other cores poll from an IPI, so this never waits for them
+pub fn poll_all() {
+	poll();
+	ipi::send(ipi::Target::AllButSelf, InterruptIndex::McePoll as u8);
+}
+
+/// Body of the #MC handler. Every bank holding an error is logged and cleared. Execution goes
+/// on if all of them were corrected and the interrupted code can be restarted, uncorrected or
+/// context corrupting errors bring the kernel down
+pub fn handle(stack_frame: &InterruptStackFrame) {
+	let mut mcg_status = Msr::new(IA32_MCG_STATUS);
+	let status_bits = unsafe { mcg_status.read() };
+	println!("EXCEPTION: MACHINE CHECK (MCG_STATUS {:#x})", status_bits);
+
+	let mut fatal = 0;
+	for status in (0..banks()).filter_map(BankStatus::read) {
+		println!("{}", status);
+		if status.uncorrected() || status.context_corrupt() {
+			fatal += 1;
+		} else {
+			CORRECTED.fetch_add(1, Ordering::Relaxed);
+		}
+		status.clear();
+	}
+
+	if fatal > 0 {
+		panic!("{} uncorrected machine check error(s)\n{:#?}", fatal, stack_frame);
+	}
+	if status_bits & MCG_STATUS_RIPV == 0 {
+		panic!("machine check without a restartable context\n{:#?}", stack_frame);
+	}
+	unsafe { mcg_status.write(status_bits & !MCG_STATUS_MCIP) };
+}
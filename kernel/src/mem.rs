@@ -3,11 +3,21 @@ pub use x86_64::structures::paging::Page;
 use x86_64::{
 	registers::control::Cr3,
 	structures::paging::{
-		FrameAllocator, Mapper, OffsetPageTable, PageSize, PageTable, PhysFrame, Size4KiB,
+		mapper::{FlagUpdateError, MapToError, UnmapError},
+		FrameAllocator, Mapper, OffsetPageTable, PageSize, PageTable, PageTableFlags, PhysFrame,
+		Size4KiB,
 	},
 	PhysAddr, VirtAddr,
 };
 
+use crate::{ipi, once_lock::OnceLock};
+
+/// End of the lower half, the only part of the address space ring 3 may use
+const USER_END: u64 = 1 << 47;
+
+/// Virtual address where the bootloader mapped the whole physical memory
+pub static PHYS_OFFSET: OnceLock<VirtAddr> = OnceLock::new();
+
 /// Return the VirtAddr for the Paging Table N. 4
 ///
 /// # Safety
@@ -25,10 +35,118 @@ unsafe fn active_level_4_table(offset: VirtAddr) -> &'static mut PageTable {
 ///
 /// .
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+	let _ = PHYS_OFFSET.set(physical_memory_offset);
 	let level_4_table = active_level_4_table(physical_memory_offset);
 	OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Returns the address through which the kernel can access the physical address `addr`
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+	*PHYS_OFFSET.get().expect("mem::init was not called") + addr.as_u64()
+}
+
+/// Walks the active page tables without modifying them, returns `None` for unmapped addresses
+/// or before [`init`]. Safe to use from panic and exception handlers
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> { walk(addr).map(|(phys, _)| phys) }
+
+/// Whether ring 3 code may read `len` bytes at `addr`, and write them if `write`. System calls
+/// check the buffers they are handed with it before touching them
+pub fn user_accessible(addr: VirtAddr, len: usize, write: bool) -> bool {
+	let mut required = PageTableFlags::USER_ACCESSIBLE;
+	if write {
+		required |= PageTableFlags::WRITABLE;
+	}
+	if len == 0 {
+		return true;
+	}
+	let Some(last) = addr.as_u64().checked_add(len as u64 - 1) else { return false };
+	if last >= USER_END {
+		return false;
+	}
+
+	let pages = Page::<Size4KiB>::range_inclusive(
+		Page::containing_address(addr),
+		Page::containing_address(VirtAddr::new(last)),
+	);
+	pages
+		.into_iter()
+		.all(|page| walk(page.start_address()).is_some_and(|(_, flags)| flags.contains(required)))
+}
+
+/// Like [`translate`], also returning the flags in effect, those set at every level
+fn walk(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+	let offset = *PHYS_OFFSET.get()?;
+	let (mut table_frame, _) = Cr3::read();
+	let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+	let mut flags = PageTableFlags::all();
+
+	for (level, index) in indexes.into_iter().enumerate() {
+		let table =
+			unsafe { &*(offset + table_frame.start_address().as_u64()).as_ptr::<PageTable>() };
+		let entry = &table[index];
+		if !entry.flags().contains(PageTableFlags::PRESENT) {
+			return None;
+		}
+		flags &= entry.flags();
+
+		let huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+		let offset_mask: u64 = match (level, huge) {
+			(1, true) => (1 << 30) - 1,
+			(2, true) => (1 << 21) - 1,
+			(3, _) => (1 << 12) - 1,
+			_ => {
+				table_frame = PhysFrame::containing_address(entry.addr());
+				continue;
+			}
+		};
+		return Some((entry.addr() + (addr.as_u64() & offset_mask), flags));
+	}
+	None
+}
+
+/// Unmaps `page` and invalidates it in the TLB of every core, returns the frame it pointed to
+pub fn unmap_page(mapper: &mut impl Mapper<Size4KiB>, page: Page) -> Result<PhysFrame, UnmapError> {
+	let (frame, flush) = mapper.unmap(page)?;
+	flush.flush();
+	ipi::shootdown(page.start_address(), 1);
+	Ok(frame)
+}
+
+/// Changes the flags of `page` and invalidates it in the TLB of every core
+///
+/// # Safety
+///
+/// Same as [`Mapper::update_flags`], the new flags must not break memory safety
+pub unsafe fn update_page_flags(
+	mapper: &mut impl Mapper<Size4KiB>,
+	page: Page,
+	flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+	mapper.update_flags(page, flags)?.flush();
+	ipi::shootdown(page.start_address(), 1);
+	Ok(())
+}
+
+/// Maps `page` to a fresh zeroed frame that ring 3 code may access with `flags`, on top of
+/// present and user accessible. The tables above it become user accessible too, the entries of
+/// the kernel pages keep them out of reach
+pub fn map_user_page(
+	mapper: &mut impl Mapper<Size4KiB>,
+	frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+	page: Page,
+	flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+	// A `syscall` at its very end would return to a non-canonical address, `sysret` faults on
+	// that still in ring 0
+	assert!(page.start_address().as_u64() < USER_END - Size4KiB::SIZE, "user page too high");
+	let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+	unsafe { phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, 4096) };
+
+	let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+	unsafe { mapper.map_to(page, frame, flags, frame_allocator)? }.flush();
+	Ok(())
+}
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
 	memory_map: &'static MemoryRegions,
@@ -1,5 +1,20 @@
-use core::cell::UnsafeCell;
-use core::sync::atomic::{fence, AtomicBool, Ordering};
+use core::{arch::x86_64::_rdtsc, cell::UnsafeCell, panic::Location, ptr::null_mut};
+
+use crate::{
+	println,
+	sync::{
+		atomic::{fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+		interrupts, spin_loop,
+	},
+	time,
+	watchdog::{self, HeldLock},
+};
+
+/// Locks with statistics that can be listed by [`dump_lock_stats`] at most
+const MAX_LOCK_STATS: usize = 32;
+
+static LOCK_STATS: [AtomicPtr<LockStats>; MAX_LOCK_STATS] =
+	[const { AtomicPtr::new(null_mut()) }; MAX_LOCK_STATS];
 
 #[derive(Debug)]
 pub struct Mutex<T> {
@@ -10,6 +25,7 @@ pub struct Mutex<T> {
 #[derive(Debug)]
 pub struct MutexGuard<'a, T> {
 	mtx: &'a Mutex<T>,
+	_held: Option<HeldLock>,
 }
 
 impl<T> Drop for MutexGuard<'_, T> {
@@ -31,10 +47,13 @@ impl<T> Mutex<T> {
 		Mutex { lock: AtomicBool::new(false), cell: UnsafeCell::new(value) }
 	}
 
+	#[track_caller]
 	pub fn lock(&self) -> MutexGuard<'_, T> {
+		#[cfg(feature = "lockdep")]
+		crate::lockdep::acquire(self as *const _ as usize, Location::caller());
 		'block: loop {
 			while self.lock.load(Ordering::Relaxed) {
-				core::hint::spin_loop()
+				spin_loop()
 			}
 
 			let stored =
@@ -47,12 +66,430 @@ impl<T> Mutex<T> {
 		}
 
 		fence(Ordering::Acquire);
-		MutexGuard { mtx: self }
+		let held = watchdog::lock_acquired(self as *const _ as usize, Location::caller());
+		MutexGuard { mtx: self, _held: held }
+	}
+
+	/// Takes the lock only if it is free right now
+	#[track_caller]
+	pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+		self.lock.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).ok()?;
+		#[cfg(feature = "lockdep")]
+		crate::lockdep::try_acquired(self as *const _ as usize, Location::caller());
+		let held = watchdog::lock_acquired(self as *const _ as usize, Location::caller());
+		Some(MutexGuard { mtx: self, _held: held })
+	}
+
+	/// Spins for the lock for at most `timeout_ms` milliseconds of the kernel clock, which only
+	/// advances while the boot processor takes timer interrupts
+	#[track_caller]
+	pub fn lock_timeout(&self, timeout_ms: u64) -> Option<MutexGuard<'_, T>> {
+		let deadline = time::uptime_ms().saturating_add(timeout_ms);
+		loop {
+			if !self.lock.load(Ordering::Relaxed) {
+				if let Some(guard) = self.try_lock() {
+					return Some(guard);
+				}
+			}
+			if time::uptime_ms() >= deadline {
+				return None;
+			}
+			spin_loop();
+		}
+	}
+
+	/// Releases the lock without its guard, so the lockup and panic paths can print while a
+	/// stuck core holds the console
+	///
+	/// # Safety
+	///
+	/// Whoever holds the guard must never use it again
+	pub unsafe fn force_unlock(&self) { self.lock.store(false, Ordering::Release); }
+}
+
+/// A spinning reader-writer lock. Any number of readers or a single writer hold it at a time.
+///
+/// Waiting writers keep new readers out so they cannot starve, which also means a core taking
+/// the read lock twice deadlocks if a writer arrives in between
+#[derive(Debug)]
+pub struct RwLock<T> {
+	/// Reader count, or [`RwLock::WRITER`] while written
+	state: AtomicUsize,
+	waiting_writers: AtomicUsize,
+	cell: UnsafeCell<T>,
+}
+
+#[derive(Debug)]
+pub struct RwLockReadGuard<'a, T> {
+	lock: &'a RwLock<T>,
+	_held: Option<HeldLock>,
+}
+
+#[derive(Debug)]
+pub struct RwLockWriteGuard<'a, T> {
+	lock: &'a RwLock<T>,
+	_held: Option<HeldLock>,
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+	fn drop(&mut self) { self.lock.state.fetch_sub(1, Ordering::Release); }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+	fn drop(&mut self) { self.lock.state.store(0, Ordering::Release); }
+}
+
+impl<T> core::ops::Deref for RwLockReadGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &Self::Target { unsafe { &*self.lock.cell.get() } }
+}
+
+impl<T> core::ops::Deref for RwLockWriteGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &Self::Target { unsafe { &*self.lock.cell.get() } }
+}
+
+impl<T> core::ops::DerefMut for RwLockWriteGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut Self::Target { unsafe { &mut *self.lock.cell.get() } }
+}
+
+impl<T> RwLock<T> {
+	const WRITER: usize = usize::MAX;
+
+	pub const fn new(value: T) -> RwLock<T> {
+		RwLock {
+			state: AtomicUsize::new(0),
+			waiting_writers: AtomicUsize::new(0),
+			cell: UnsafeCell::new(value),
+		}
+	}
+
+	#[track_caller]
+	pub fn read(&self) -> RwLockReadGuard<'_, T> {
+		#[cfg(feature = "lockdep")]
+		crate::lockdep::acquire_read(self as *const _ as usize, Location::caller());
+		loop {
+			if let Some(guard) = self.try_read() {
+				return guard;
+			}
+			spin_loop();
+		}
+	}
+
+	#[track_caller]
+	pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+		#[cfg(feature = "lockdep")]
+		crate::lockdep::acquire(self as *const _ as usize, Location::caller());
+		self.write_until(None).unwrap()
+	}
+
+	/// Takes the read lock only if no writer holds it or waits for it
+	#[track_caller]
+	pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+		let state = self.state.load(Ordering::Relaxed);
+		if state == Self::WRITER || self.waiting_writers.load(Ordering::Relaxed) > 0 {
+			return None;
+		}
+
+		self.state.compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed).ok()?;
+		#[cfg(feature = "lockdep")]
+		crate::lockdep::try_acquired(self as *const _ as usize, Location::caller());
+		let held = watchdog::read_lock_acquired(self as *const _ as usize, Location::caller());
+		Some(RwLockReadGuard { lock: self, _held: held })
+	}
+
+	/// Takes the write lock only if nobody holds it
+	#[track_caller]
+	pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+		self.state.compare_exchange(0, Self::WRITER, Ordering::Acquire, Ordering::Relaxed).ok()?;
+		#[cfg(feature = "lockdep")]
+		crate::lockdep::try_acquired(self as *const _ as usize, Location::caller());
+		let held = watchdog::lock_acquired(self as *const _ as usize, Location::caller());
+		Some(RwLockWriteGuard { lock: self, _held: held })
+	}
+
+	/// See [`Mutex::lock_timeout`]
+	#[track_caller]
+	pub fn read_timeout(&self, timeout_ms: u64) -> Option<RwLockReadGuard<'_, T>> {
+		let deadline = time::uptime_ms().saturating_add(timeout_ms);
+		loop {
+			if let Some(guard) = self.try_read() {
+				return Some(guard);
+			}
+			if time::uptime_ms() >= deadline {
+				return None;
+			}
+			spin_loop();
+		}
+	}
+
+	/// See [`Mutex::lock_timeout`]
+	#[track_caller]
+	pub fn write_timeout(&self, timeout_ms: u64) -> Option<RwLockWriteGuard<'_, T>> {
+		self.write_until(Some(time::uptime_ms().saturating_add(timeout_ms)))
+	}
+
+	/// Spins for the write lock until the kernel clock reaches `deadline`, holding readers back
+	/// meanwhile
+	#[track_caller]
+	fn write_until(&self, deadline: Option<u64>) -> Option<RwLockWriteGuard<'_, T>> {
+		if let Some(guard) = self.try_write() {
+			return Some(guard);
+		}
+
+		self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+		let guard = loop {
+			if let Some(guard) = self.try_write() {
+				break Some(guard);
+			}
+			if deadline.is_some_and(|deadline| time::uptime_ms() >= deadline) {
+				break None;
+			}
+			spin_loop();
+		};
+		self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+		guard
+	}
+}
+
+/// Contention counters of a lock, see [`TicketMutex::with_stats`]. They register themselves
+/// for [`dump_lock_stats`] the first time the lock is taken
+#[derive(Debug)]
+pub struct LockStats {
+	name: &'static str,
+	acquisitions: AtomicU64,
+	/// Acquisitions that found the lock taken
+	contended: AtomicU64,
+	/// Spin loop iterations while waiting
+	spins: AtomicU64,
+	/// Longest time the lock was held, in TSC cycles
+	max_hold: AtomicU64,
+	registered: AtomicBool,
+}
+
+impl LockStats {
+	pub const fn new(name: &'static str) -> LockStats {
+		LockStats {
+			name,
+			acquisitions: AtomicU64::new(0),
+			contended: AtomicU64::new(0),
+			spins: AtomicU64::new(0),
+			max_hold: AtomicU64::new(0),
+			registered: AtomicBool::new(false),
+		}
+	}
+
+	fn record_acquire(&'static self, spins: u64) {
+		if !self.registered.swap(true, Ordering::Relaxed) {
+			// Silently untracked once the table is full
+			let _ = LOCK_STATS.iter().find(|slot| {
+				let this = self as *const LockStats as *mut LockStats;
+				slot.compare_exchange(null_mut(), this, Ordering::Release, Ordering::Relaxed)
+					.is_ok()
+			});
+		}
+
+		self.acquisitions.fetch_add(1, Ordering::Relaxed);
+		if spins > 0 {
+			self.contended.fetch_add(1, Ordering::Relaxed);
+			self.spins.fetch_add(spins, Ordering::Relaxed);
+		}
+	}
+
+	fn record_release(&self, held: u64) { self.max_hold.fetch_max(held, Ordering::Relaxed); }
+}
+
+/// Prints the counters of every lock with statistics that was taken at least once
+pub fn dump_lock_stats() {
+	println!(
+		"{:<16} {:>12} {:>12} {:>14} {:>14}",
+		"LOCK", "ACQUIRED", "CONTENDED", "SPINS", "MAX HOLD (cyc)"
+	);
+	for stats in LOCK_STATS.iter().map(|slot| slot.load(Ordering::Acquire)) {
+		let Some(stats) = (unsafe { stats.as_ref() }) else { continue };
+		println!(
+			"{:<16} {:>12} {:>12} {:>14} {:>14}",
+			stats.name,
+			stats.acquisitions.load(Ordering::Relaxed),
+			stats.contended.load(Ordering::Relaxed),
+			stats.spins.load(Ordering::Relaxed),
+			stats.max_hold.load(Ordering::Relaxed)
+		);
+	}
+}
+
+/// A spin lock handing itself out in the order cores asked for it, so none can starve. Takes
+/// more cache traffic than [`Mutex`] under contention, which is the price of fairness
+#[derive(Debug)]
+pub struct TicketMutex<T> {
+	/// Ticket the next core to ask gets
+	next: AtomicU32,
+	/// Ticket of the core allowed to hold the lock
+	serving: AtomicU32,
+	stats: Option<&'static LockStats>,
+	cell: UnsafeCell<T>,
+}
+
+#[derive(Debug)]
+pub struct TicketMutexGuard<'a, T> {
+	mtx: &'a TicketMutex<T>,
+	/// TSC when the lock was taken, only read with statistics on
+	acquired_at: u64,
+	_held: Option<HeldLock>,
+}
+
+impl<T> Drop for TicketMutexGuard<'_, T> {
+	fn drop(&mut self) {
+		if let Some(stats) = self.mtx.stats {
+			stats.record_release(unsafe { _rdtsc() }.saturating_sub(self.acquired_at));
+		}
+		self.mtx.serving.fetch_add(1, Ordering::Release);
 	}
 }
 
+impl<T> core::ops::Deref for TicketMutexGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &Self::Target { unsafe { &*self.mtx.cell.get() } }
+}
+
+impl<T> core::ops::DerefMut for TicketMutexGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut Self::Target { unsafe { &mut *self.mtx.cell.get() } }
+}
+
+impl<T> TicketMutex<T> {
+	pub const fn new(value: T) -> TicketMutex<T> {
+		TicketMutex {
+			next: AtomicU32::new(0),
+			serving: AtomicU32::new(0),
+			stats: None,
+			cell: UnsafeCell::new(value),
+		}
+	}
+
+	/// Like [`TicketMutex::new`], counting acquisitions, contention and hold times into `stats`
+	pub const fn with_stats(value: T, stats: &'static LockStats) -> TicketMutex<T> {
+		TicketMutex {
+			next: AtomicU32::new(0),
+			serving: AtomicU32::new(0),
+			stats: Some(stats),
+			cell: UnsafeCell::new(value),
+		}
+	}
+
+	#[track_caller]
+	pub fn lock(&self) -> TicketMutexGuard<'_, T> {
+		#[cfg(feature = "lockdep")]
+		crate::lockdep::acquire(self as *const _ as usize, Location::caller());
+		let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+		let mut spins = 0;
+		while self.serving.load(Ordering::Acquire) != ticket {
+			spin_loop();
+			spins += 1;
+		}
+
+		let acquired_at = match self.stats {
+			Some(stats) => {
+				stats.record_acquire(spins);
+				unsafe { _rdtsc() }
+			}
+			None => 0,
+		};
+		let held = watchdog::lock_acquired(self as *const _ as usize, Location::caller());
+		TicketMutexGuard { mtx: self, acquired_at, _held: held }
+	}
+
+	/// Hands the lock to the next waiter without the holder's guard, see [`Mutex::force_unlock`]
+	///
+	/// # Safety
+	///
+	/// Whoever holds the guard must never use it again
+	pub unsafe fn force_unlock(&self) { self.serving.fetch_add(1, Ordering::Release); }
+}
+
+unsafe impl<T> Sync for TicketMutex<T> where T: Send {}
+
+unsafe impl<T> Send for TicketMutex<T> where T: Send {}
+
+/// A [`TicketMutex`] that also disables interrupts on the current core while held, for state
+/// that interrupt handlers touch too. With a plain [`Mutex`] a handler interrupting the holder
+/// on the same core would spin forever
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct IrqMutex<T> {
+	inner: TicketMutex<T>,
+}
+
+#[derive(Debug)]
+pub struct IrqMutexGuard<'a, T> {
+	/// Declared first so the lock is released before interrupts come back
+	guard: TicketMutexGuard<'a, T>,
+	_interrupts: SavedInterrupts,
+}
+
+/// Re-enables interrupts when dropped if they were enabled when it was created
+#[derive(Debug)]
+struct SavedInterrupts {
+	were_enabled: bool,
+}
+
+impl SavedInterrupts {
+	fn disable() -> SavedInterrupts {
+		let were_enabled = interrupts::are_enabled();
+		interrupts::disable();
+		SavedInterrupts { were_enabled }
+	}
+}
+
+impl Drop for SavedInterrupts {
+	fn drop(&mut self) {
+		if self.were_enabled {
+			interrupts::enable();
+		}
+	}
+}
+
+impl<T> core::ops::Deref for IrqMutexGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &Self::Target { &self.guard }
+}
+
+impl<T> core::ops::DerefMut for IrqMutexGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut Self::Target { &mut self.guard }
+}
+
+impl<T> IrqMutex<T> {
+	pub const fn new(value: T) -> IrqMutex<T> { IrqMutex { inner: TicketMutex::new(value) } }
+
+	/// See [`TicketMutex::with_stats`]
+	pub const fn with_stats(value: T, stats: &'static LockStats) -> IrqMutex<T> {
+		IrqMutex { inner: TicketMutex::with_stats(value, stats) }
+	}
+
+	#[track_caller]
+	pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+		let interrupts = SavedInterrupts::disable();
+		IrqMutexGuard { guard: self.inner.lock(), _interrupts: interrupts }
+	}
+
+	/// See [`TicketMutex::force_unlock`]
+	///
+	/// # Safety
+	///
+	/// Whoever holds the guard must never use it again
+	pub unsafe fn force_unlock(&self) { self.inner.force_unlock() }
+}
+
 /// A reference to a single mutex can be shared between threads if the inner value `T` is sendable, thus implements `Send`
 unsafe impl<T> Sync for Mutex<T> where T: Send {}
 
 /// A mutex can be sent to other threads
 unsafe impl<T> Send for Mutex<T> where T: Send {}
+
+/// Readers share `&T` across threads, so `T` must be `Sync` as well
+unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+
+unsafe impl<T> Send for RwLock<T> where T: Send {}
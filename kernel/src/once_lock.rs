@@ -1,6 +1,9 @@
 use core::cell::UnsafeCell;
+use core::convert::Infallible;
 use core::mem::MaybeUninit;
-use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::sync::atomic::{AtomicU8, Ordering};
+use crate::sync::spin_loop;
 
 pub struct OnceLock<T: Send> {
 	cell: UnsafeCell<MaybeUninit<T>>,
@@ -38,6 +41,80 @@ impl<T: Send> OnceLock<T> {
 		(self.state.load(Ordering::Acquire) == Self::FILLED)
 			.then(|| unsafe { { &*self.cell.get() }.assume_init_ref() })
 	}
+
+	/// Returns the value, initializing it with `f` if it is empty. If another CPU is running its
+	/// initializer this one waits for it instead. Calling it on the same lock from within `f`
+	/// spins forever
+	pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+		match self.get_or_try_init(|| Ok::<T, Infallible>(f())) {
+			Ok(value) => value,
+			Err(never) => match never {},
+		}
+	}
+
+	/// Like [`OnceLock::get_or_init`], but `f` may fail. The lock stays empty then, and the next
+	/// caller, possibly one that was waiting, gets to try
+	pub fn get_or_try_init<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+		loop {
+			if let Some(value) = self.get() {
+				return Ok(value);
+			}
+
+			let stored = self.state.compare_exchange(
+				Self::EMPTY,
+				Self::WRITING,
+				Ordering::Acquire,
+				Ordering::Relaxed,
+			);
+
+			if stored.is_ok() {
+				return match f() {
+					Ok(value) => {
+						let value = unsafe { &mut *self.cell.get() }.write(value);
+						self.state.store(Self::FILLED, Ordering::Release);
+						Ok(value)
+					}
+					Err(err) => {
+						self.state.store(Self::EMPTY, Ordering::Release);
+						Err(err)
+					}
+				};
+			}
+
+			spin_loop()
+		}
+	}
+
+	/// Spins until some other CPU fills the lock
+	pub fn wait(&self) -> &T {
+		loop {
+			if let Some(value) = self.get() {
+				return value;
+			}
+			spin_loop()
+		}
+	}
+
+	/// Empties the lock, returning the value it held
+	pub fn take(&mut self) -> Option<T> {
+		if *self.state.get_mut() != Self::FILLED {
+			return None;
+		}
+
+		*self.state.get_mut() = Self::EMPTY;
+		Some(unsafe { self.cell.get_mut().assume_init_read() })
+	}
+
+	pub fn into_inner(mut self) -> Option<T> { self.take() }
+}
+
+impl<T: Send + core::fmt::Debug> core::fmt::Debug for OnceLock<T> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self.get() {
+			Some(value) => f.debug_tuple("OnceLock").field(value).finish(),
+			None => f.write_str("OnceLock(<uninit>)"),
+		}
+	}
 }
 
 impl<T: Send> Default for OnceLock<T> {
@@ -47,7 +124,7 @@ impl<T: Send> Default for OnceLock<T> {
 impl<T: Send> Drop for OnceLock<T> {
 	fn drop(&mut self) {
 		while self.state.load(Ordering::Relaxed) == Self::WRITING {
-			core::hint::spin_loop()
+			spin_loop()
 		}
 
 		if self.state.load(Ordering::Acquire) == Self::FILLED {
@@ -61,3 +138,47 @@ unsafe impl<T> Send for OnceLock<T> where T: Send {}
 
 /// A reference to a OnceLock may be shared between threads only if the inner value T is shareable and sendable
 unsafe impl<T> Sync for OnceLock<T> where T: Send + Sync {}
+
+/// A value computed by `F` the first time it is dereferenced, meant for statics that cannot be
+/// built in a `const` context
+pub struct Lazy<T: Send, F = fn() -> T> {
+	cell: OnceLock<T>,
+	init: UnsafeCell<Option<F>>,
+}
+
+impl<T: Send, F: FnOnce() -> T> Lazy<T, F> {
+	pub const fn new(init: F) -> Lazy<T, F> {
+		Lazy { cell: OnceLock::new(), init: UnsafeCell::new(Some(init)) }
+	}
+
+	/// Runs the initializer now if nobody did yet, to keep it out of a later, more delicate
+	/// context such as an interrupt handler
+	pub fn force(this: &Lazy<T, F>) -> &T {
+		// Only the CPU that won the right to fill the cell gets here, so it alone touches `init`
+		this.cell.get_or_init(|| match unsafe { &mut *this.init.get() }.take() {
+			Some(init) => init(),
+			None => unreachable!("Lazy initializer ran twice"),
+		})
+	}
+
+	/// Returns the value if the initializer already ran, without running it
+	pub fn get(this: &Lazy<T, F>) -> Option<&T> { this.cell.get() }
+}
+
+impl<T: Send, F: FnOnce() -> T> core::ops::Deref for Lazy<T, F> {
+	type Target = T;
+
+	fn deref(&self) -> &Self::Target { Lazy::force(self) }
+}
+
+impl<T: Send + core::fmt::Debug, F> core::fmt::Debug for Lazy<T, F> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self.cell.get() {
+			Some(value) => f.debug_tuple("Lazy").field(value).finish(),
+			None => f.write_str("Lazy(<uninit>)"),
+		}
+	}
+}
+
+/// The initializer runs on whichever CPU gets there first, so it must be sendable too
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
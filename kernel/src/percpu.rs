@@ -0,0 +1,210 @@
+use core::{
+	arch::asm,
+	sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering},
+};
+
+use x86_64::{
+	registers::model_specific::{GsBase, KernelGsBase},
+	structures::{gdt::GlobalDescriptorTable, idt::InterruptStackFrame},
+	PrivilegeLevel, VirtAddr,
+};
+
+use crate::{
+	gdt::{Selectors, Tss},
+	interrupts::IrqStats,
+	once_lock::OnceLock,
+	smp::MAX_CPUS,
+	watchdog::{HeldLocks, Watchdog},
+};
+
+static PER_CPU: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+/// Accesses a field of the [`PerCpu`] area of the current core.
+///
+/// The reference may belong to another core by the time it is used if the caller can migrate,
+/// which is why every mutable field is atomic.
+#[macro_export]
+macro_rules! percpu {
+	($field:ident) => {
+		&$crate::percpu::current().$field
+	};
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct PerCpu {
+	/// Address of this structure, must stay the first field so `gs:[0]` yields it
+	this: AtomicUsize,
+	id: AtomicUsize,
+	apic_id: AtomicU32,
+	irq_depth: AtomicU32,
+	/// [`Console`]s the core is printing to, as a bit mask
+	printing: AtomicU8,
+	/// Id of the thread running on this core, the id of its idle thread when nothing else is ready
+	pub current_task: AtomicUsize,
+	/// Top of the kernel stack of the running thread, where `syscall` entries switch to
+	pub kernel_stack: AtomicUsize,
+	/// Stack pointer of ring 3, saved by `syscall` entries until they reach the kernel stack
+	pub user_stack: AtomicUsize,
+	pub tss: OnceLock<Tss>,
+	pub gdt: OnceLock<(GlobalDescriptorTable, Selectors)>,
+	pub irq_stats: IrqStats,
+	pub watchdog: Watchdog,
+	pub held_locks: HeldLocks,
+}
+
+impl PerCpu {
+	const fn new() -> PerCpu {
+		PerCpu {
+			this: AtomicUsize::new(0),
+			id: AtomicUsize::new(0),
+			apic_id: AtomicU32::new(0),
+			irq_depth: AtomicU32::new(0),
+			printing: AtomicU8::new(0),
+			current_task: AtomicUsize::new(0),
+			kernel_stack: AtomicUsize::new(0),
+			user_stack: AtomicUsize::new(0),
+			tss: OnceLock::new(),
+			gdt: OnceLock::new(),
+			irq_stats: IrqStats::new(),
+			watchdog: Watchdog::new(),
+			held_locks: HeldLocks::new(),
+		}
+	}
+
+	pub fn id(&self) -> usize { self.id.load(Ordering::Relaxed) }
+
+	pub fn apic_id(&self) -> u32 { self.apic_id.load(Ordering::Relaxed) }
+
+	pub fn set_apic_id(&self, apic_id: u32) { self.apic_id.store(apic_id, Ordering::Relaxed) }
+
+	/// How many interrupt handlers are currently nested on this core
+	pub fn irq_depth(&self) -> u32 { self.irq_depth.load(Ordering::Relaxed) }
+
+	pub fn in_interrupt(&self) -> bool { self.irq_depth() > 0 }
+}
+
+/// Consoles whose lock an NMI or a machine check may find held by the very core it interrupted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Console {
+	Framebuffer = 1 << 0,
+	Serial      = 1 << 1,
+}
+
+/// Marks the current core as printing to a console until dropped, see [`start_printing`]
+#[derive(Debug)]
+pub struct Printing {
+	/// `None` before the core has its area, interrupts that print do not run yet then
+	cpu: Option<&'static PerCpu>,
+	console: Console,
+}
+
+impl Drop for Printing {
+	fn drop(&mut self) {
+		if let Some(cpu) = self.cpu {
+			cpu.printing.fetch_and(!(self.console as u8), Ordering::Relaxed);
+		}
+	}
+}
+
+/// Marks the current core as running an interrupt handler until dropped
+#[derive(Debug)]
+pub struct IrqContext {
+	cpu: &'static PerCpu,
+}
+
+impl Drop for IrqContext {
+	fn drop(&mut self) { self.cpu.irq_depth.fetch_sub(1, Ordering::Relaxed); }
+}
+
+/// Keeps the kernel GS base loaded while alive when the interrupt it was created for arrived in
+/// ring 3. Interrupt handlers create it before touching per-CPU data, and drop it last
+#[derive(Debug)]
+pub struct KernelGs {
+	swapped: bool,
+}
+
+impl KernelGs {
+	pub fn enter(stack_frame: &InterruptStackFrame) -> KernelGs {
+		let swapped = stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3;
+		if swapped {
+			unsafe { swapgs() };
+		}
+		KernelGs { swapped }
+	}
+
+	/// Like [`KernelGs::enter`], for the NMIs and aborts that may also hit the kernel between
+	/// its `swapgs` and the return to ring 3. Goes by the GS base itself, user code always runs
+	/// with a null one
+	pub fn enter_paranoid() -> KernelGs {
+		let swapped = GsBase::read().is_null();
+		if swapped {
+			unsafe { swapgs() };
+		}
+		KernelGs { swapped }
+	}
+}
+
+impl Drop for KernelGs {
+	fn drop(&mut self) {
+		if self.swapped {
+			unsafe { swapgs() };
+		}
+	}
+}
+
+/// Points the GS base of the calling core at the area of `cpu`. Must run before anything on
+/// the core touches per-CPU data, interrupt handlers and [`crate::mutex::Mutex::lock`] included.
+///
+/// While in the kernel `IA32_GS_BASE` holds the per-CPU area and `IA32_KERNEL_GS_BASE` the user
+/// value, so every entry from ring 3 must [`swapgs`] first and again right before returning
+pub fn init(cpu: usize) -> &'static PerCpu {
+	let area = &PER_CPU[cpu];
+	area.this.store(area as *const PerCpu as usize, Ordering::Relaxed);
+	area.id.store(cpu, Ordering::Relaxed);
+
+	GsBase::write(VirtAddr::from_ptr(area));
+	KernelGsBase::write(VirtAddr::zero());
+	area
+}
+
+/// Returns the area of the calling core
+pub fn current() -> &'static PerCpu {
+	let this: usize;
+	unsafe { asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly)) };
+	unsafe { &*(this as *const PerCpu) }
+}
+
+/// Marks the calling core as printing to `console`. `None` if it already is: the caller
+/// interrupted the core's own printing, an NMI or a machine check, and waiting for the console
+/// lock would never end
+pub fn start_printing(console: Console) -> Option<Printing> {
+	// No area yet early in boot
+	if GsBase::read().is_null() {
+		return Some(Printing { cpu: None, console });
+	}
+
+	let cpu = current();
+	if cpu.printing.fetch_or(console as u8, Ordering::Relaxed) & console as u8 != 0 {
+		return None;
+	}
+	Some(Printing { cpu: Some(cpu), console })
+}
+
+/// Returns the area of any core, to inspect other cores' state
+pub fn get(cpu: usize) -> &'static PerCpu { &PER_CPU[cpu] }
+
+pub fn enter_irq() -> IrqContext {
+	let cpu = current();
+	cpu.irq_depth.fetch_add(1, Ordering::Relaxed);
+	IrqContext { cpu }
+}
+
+/// Exchanges the GS base with the value saved in `IA32_KERNEL_GS_BASE`
+///
+/// # Safety
+///
+/// Must only be used on transitions between user and kernel mode, in pairs
+#[inline(always)]
+pub unsafe fn swapgs() { asm!("swapgs", options(nostack, preserves_flags)) }
@@ -0,0 +1,426 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use acpi::{
+	address::{AddressSpace, GenericAddress},
+	fadt::Fadt,
+	AmlTable, PhysicalMapping,
+};
+use aml::{value::Args, AmlContext, AmlName, AmlValue, DebugVerbosity};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{
+	acpi_tables, acpi_tables::KernelAcpiHandler, interrupts, mem, once_lock::OnceLock, println, smp,
+};
+
+/// PM1 control register bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+
+/// PM1 status and enable register bits of the fixed events
+const PWRBTN: u16 = 1 << 8;
+const SLPBTN: u16 = 1 << 9;
+
+/// The 8259 expects the SCI on this line, anything else would need the I/O APIC
+const SCI_IRQ: u16 = 9;
+/// Edge/Level Control Register of the slave PIC, IRQ 9 is bit 1
+const ELCR_SLAVE: u16 = 0x4D1;
+
+const KBD_CONTROLLER: u16 = 0x64;
+const KBD_RESET_LINE: u8 = 0xFE;
+/// Status reads before giving up on the 8042 input buffer. Every read of a legacy port takes
+/// about a microsecond, which bounds the wait to a few milliseconds without a timer
+const KBD_DRAIN_POLLS: u32 = 5_000;
+
+/// I/O port of QEMU's `isa-debug-exit` device, as configured by the runner
+const QEMU_EXIT_PORT: u16 = 0xF4;
+
+/// Status and enable registers of the PM1 event blocks, filled by [`init_sci`]
+static PM1_EVENTS: OnceLock<(EventBlock, Option<EventBlock>)> = OnceLock::new();
+/// Bit mask of the [`Event`]s raised by the SCI and not handled yet
+static PENDING_EVENTS: AtomicU8 = AtomicU8::new(0);
+
+/// Fixed ACPI events the kernel reacts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Event {
+	PowerButton = 1 << 0,
+	SleepButton = 1 << 1,
+}
+
+/// A PM1 event block is split in two halves, the status register followed by the enable one
+#[derive(Debug, Clone, Copy)]
+struct EventBlock {
+	status: GenericAddress,
+	enable: GenericAddress,
+}
+
+impl EventBlock {
+	fn new(block: GenericAddress) -> EventBlock {
+		let half = block.bit_width / 2;
+		EventBlock {
+			status: GenericAddress { bit_width: half, ..block },
+			enable: GenericAddress {
+				bit_width: half,
+				address: block.address + half as u64 / 8,
+				..block
+			},
+		}
+	}
+}
+
+/// Value written to `isa-debug-exit`, QEMU exits with `(code << 1) | 1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+	Success = 0x10,
+	Failed  = 0x11,
+}
+
+/// Exits QEMU through the `isa-debug-exit` device. Returns if the device is not there
+pub fn qemu_exit(code: QemuExitCode) {
+	unsafe { Port::<u32>::new(QEMU_EXIT_PORT).write(code as u32) };
+}
+
+/// Powers the machine off by entering the ACPI S5 sleep state. Halts forever if that fails
+pub fn shutdown() -> ! {
+	x86_64::instructions::interrupts::disable();
+
+	#[cfg(feature = "qemu-exit")]
+	qemu_exit(QemuExitCode::Success);
+
+	match fadt() {
+		Some(fadt) => enter_s5(&fadt),
+		None => println!("power: no FADT, cannot power off"),
+	}
+
+	println!("power: shutdown failed, it is now safe to turn off the machine");
+	crate::hlt_loop()
+}
+
+/// Resets the machine through the FADT reset register, then the 8042 reset line, then a triple
+/// fault
+pub fn reboot() -> ! {
+	x86_64::instructions::interrupts::disable();
+
+	if let Some(fadt) = fadt() {
+		// The reset register is only meaningful when the firmware says it is wired up
+		let flags = fadt.flags;
+		if flags.supports_system_reset_via_fadt() {
+			if let Ok(reset) = fadt.reset_register() {
+				let value = fadt.reset_value;
+				write_register(&reset, value as u64);
+				settle();
+			}
+		}
+	}
+
+	unsafe {
+		let mut controller = Port::<u8>::new(KBD_CONTROLLER);
+		// Wait for the input buffer to drain before sending the command. Machines without an
+		// 8042 float the bus high, so the wait is bounded and the command is sent regardless
+		for _ in 0..KBD_DRAIN_POLLS {
+			if controller.read() & 0b10 == 0 {
+				break;
+			}
+		}
+		controller.write(KBD_RESET_LINE);
+	}
+	settle();
+
+	// Nothing can handle the breakpoint without an IDT, so the CPU triple faults and resets
+	unsafe {
+		x86_64::instructions::tables::lidt(&x86_64::structures::DescriptorTablePointer {
+			limit: 0,
+			base: x86_64::VirtAddr::zero(),
+		});
+		core::arch::asm!("int3", options(nomem, nostack));
+	}
+	crate::hlt_loop()
+}
+
+/// Stops the other cores and powers off, what the power button ends up doing
+pub fn orderly_shutdown() -> ! {
+	println!("power: shutting down");
+	smp::stop_aps();
+	shutdown()
+}
+
+/// Routes the SCI through the PIC and enables the power and sleep button events
+pub fn init_sci() {
+	let Some(fadt) = fadt() else { return };
+
+	let sci = fadt.sci_interrupt;
+	if sci != SCI_IRQ {
+		println!("power: SCI on IRQ {} is not supported, buttons are ignored", sci);
+		return;
+	}
+
+	let (Ok(pm1a), Ok(pm1a_control)) = (fadt.pm1a_event_block(), fadt.pm1a_control_block()) else {
+		println!("power: no PM1a blocks, buttons are ignored");
+		return;
+	};
+	enable_acpi(&fadt, &pm1a_control);
+
+	let pm1b = fadt.pm1b_event_block().ok().flatten().map(EventBlock::new);
+	if PM1_EVENTS.set((EventBlock::new(pm1a), pm1b)).is_err() {
+		return;
+	}
+
+	let (pm1a, pm1b) = PM1_EVENTS.get().unwrap();
+	for block in core::iter::once(pm1a).chain(pm1b) {
+		// Status bits are cleared by writing ones, stale presses must not fire right away
+		write_register(&block.status, (PWRBTN | SLPBTN) as u64);
+		let enable = read_register(&block.enable) as u16;
+		write_register(&block.enable, (enable | PWRBTN | SLPBTN) as u64);
+	}
+
+	unsafe {
+		// The SCI is a shareable, level-triggered, active-low interrupt
+		let mut elcr = Port::<u8>::new(ELCR_SLAVE);
+		let level = elcr.read() | 1 << (SCI_IRQ - 8);
+		elcr.write(level);
+
+		let mut pics = interrupts::PICS.lock();
+		let [master, slave] = pics.read_masks();
+		// IRQ 2 is the cascade from the slave
+		pics.write_masks(master & !(1 << 2), slave & !(1 << (SCI_IRQ - 8)));
+	}
+}
+
+/// Body of the SCI handler. Acknowledges the fixed events that fired and queues them for
+/// [`handle_events`], the interrupt is no place to shut the machine down
+pub fn handle_sci() {
+	let Some((pm1a, pm1b)) = PM1_EVENTS.get() else { return };
+
+	for block in core::iter::once(pm1a).chain(pm1b) {
+		let status = read_register(&block.status) as u16 & read_register(&block.enable) as u16;
+		let fired = status & (PWRBTN | SLPBTN);
+		if fired == 0 {
+			continue;
+		}
+
+		write_register(&block.status, fired as u64);
+		if fired & PWRBTN != 0 {
+			PENDING_EVENTS.fetch_or(Event::PowerButton as u8, Ordering::Release);
+		}
+		if fired & SLPBTN != 0 {
+			PENDING_EVENTS.fetch_or(Event::SleepButton as u8, Ordering::Release);
+		}
+	}
+}
+
+/// Acts on the events queued by the SCI. Called by the boot processor whenever it wakes up
+pub fn handle_events() {
+	let pending = PENDING_EVENTS.swap(0, Ordering::Acquire);
+	if pending & Event::SleepButton as u8 != 0 {
+		println!("power: sleep button pressed, sleep states are not supported");
+	}
+	if pending & Event::PowerButton as u8 != 0 {
+		println!("power: power button pressed");
+		orderly_shutdown();
+	}
+}
+
+fn fadt() -> Option<PhysicalMapping<KernelAcpiHandler, Fadt>> {
+	acpi_tables::tables().and_then(|tables| tables.find_table::<Fadt>().ok())
+}
+
+fn enter_s5(fadt: &PhysicalMapping<KernelAcpiHandler, Fadt>) {
+	let Some((slp_typ_a, slp_typ_b)) = s5_sleep_types() else {
+		println!("power: no \\_S5 object in the DSDT or SSDTs");
+		return;
+	};
+
+	let Ok(pm1a) = fadt.pm1a_control_block() else {
+		println!("power: no PM1a control block");
+		return;
+	};
+
+	enable_acpi(fadt, &pm1a);
+	let pm1b = fadt.pm1b_control_block().ok().flatten();
+
+	let sleep = |register: &GenericAddress, slp_typ: u8| {
+		let value = read_register(register) as u16 & !(0b111 << SLP_TYP_SHIFT);
+		write_register(register, (value | ((slp_typ as u16) << SLP_TYP_SHIFT) | SLP_EN) as u64);
+	};
+	sleep(&pm1a, slp_typ_a);
+	if let Some(pm1b) = pm1b {
+		sleep(&pm1b, slp_typ_b);
+	}
+	settle();
+}
+
+/// Switches the chipset from legacy to ACPI mode, unless the firmware already did
+fn enable_acpi(fadt: &Fadt, pm1a: &GenericAddress) {
+	let smi_cmd_port = fadt.smi_cmd_port;
+	let acpi_enable = fadt.acpi_enable;
+	if read_register(pm1a) as u16 & SCI_EN != 0 || smi_cmd_port == 0 || acpi_enable == 0 {
+		return;
+	}
+
+	unsafe { Port::<u8>::new(smi_cmd_port as u16).write(acpi_enable) };
+	for _ in 0..1_000_000 {
+		if read_register(pm1a) as u16 & SCI_EN != 0 {
+			return;
+		}
+		core::hint::spin_loop();
+	}
+}
+
+/// The `SLP_TYPa` and `SLP_TYPb` values of the S5 state, from the DSDT and every SSDT since
+/// firmware is free to define `\_S5` in either. Falls back to [`find_s5`] when the interpreter
+/// cannot make sense of the tables
+fn s5_sleep_types() -> Option<(u8, u8)> {
+	let tables = acpi_tables::tables()?;
+	let aml_tables: Vec<AmlTable> = tables.dsdt().ok().into_iter().chain(tables.ssdts()).collect();
+
+	evaluate_s5(&aml_tables).or_else(|| {
+		println!("power: could not evaluate \\_S5, scanning the tables for it");
+		aml_tables.iter().find_map(find_s5)
+	})
+}
+
+/// Loads the tables into the `aml` interpreter and evaluates `\_S5`, a package whose first two
+/// elements are `SLP_TYPa` and `SLP_TYPb`. It may be a method, or be defined in a scope or
+/// under a conditional
+fn evaluate_s5(tables: &[AmlTable]) -> Option<(u8, u8)> {
+	let mut context = AmlContext::new(Box::new(KernelAcpiHandler), DebugVerbosity::None);
+	for table in tables {
+		if let Err(error) = context.parse_table(aml_bytes(table)) {
+			println!("power: AML parse error {:?}", error);
+		}
+	}
+
+	let path = AmlName::from_str("\\_S5").ok()?;
+	// Plain objects are returned as they are, methods are run
+	let AmlValue::Package(elements) = context.invoke_method(&path, Args::default()).ok()? else {
+		return None;
+	};
+	let slp_typ = |index: usize| {
+		let value = elements.get(index)?.as_integer(&context).ok()?;
+		Some(value as u8)
+	};
+	Some((slp_typ(0)?, slp_typ(1)?))
+}
+
+fn aml_bytes(table: &AmlTable) -> &'static [u8] {
+	let start = mem::phys_to_virt(PhysAddr::new(table.address as u64));
+	unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), table.length as usize) }
+}
+
+/// Fallback for [`evaluate_s5`]: finds the `SLP_TYPa` and `SLP_TYPb` values of the `\_S5`
+/// package by matching the bytecode directly,
+/// `NameOp "_S5_" PackageOp PkgLength NumElements <int> <int> ...`.
+///
+/// This only works for the common case of a named package of constants. A `\_S5` returned by
+/// a method or picked under a conditional is missed or read wrong, and a `_S5_` byte run that
+/// happens to sit in a buffer or another name can match falsely. Every occurrence of the name
+/// is tried, the first that decodes wins
+fn find_s5(table: &AmlTable) -> Option<(u8, u8)> {
+	let aml = aml_bytes(table);
+	aml.windows(4)
+		.enumerate()
+		.filter(|(_, name)| *name == b"_S5_")
+		.find_map(|(at, _)| parse_s5(aml, at))
+}
+
+/// Decodes the package following the `_S5_` name at `at`, `None` if it is not a named package
+/// of integer constants
+fn parse_s5(aml: &[u8], at: usize) -> Option<(u8, u8)> {
+	const NAME_OP: u8 = 0x08;
+	const PACKAGE_OP: u8 = 0x12;
+	const BYTE_PREFIX: u8 = 0x0A;
+	const WORD_PREFIX: u8 = 0x0B;
+	const DWORD_PREFIX: u8 = 0x0C;
+	const QWORD_PREFIX: u8 = 0x0E;
+	const ZERO_OP: u8 = 0x00;
+	const ONE_OP: u8 = 0x01;
+
+	// The name may be written as a root path, `\_S5_`
+	let name_op = match at.checked_sub(1).map(|i| aml[i]) {
+		Some(b'\\') => at.checked_sub(2).map(|i| aml[i]),
+		other => other,
+	};
+	if name_op != Some(NAME_OP) || aml.get(at + 4) != Some(&PACKAGE_OP) {
+		return None;
+	}
+
+	// The top two bits of the PkgLength lead byte count the bytes that follow it
+	let pkg_length = at + 5;
+	let num_elements = pkg_length + 1 + (*aml.get(pkg_length)? >> 6) as usize;
+	let mut cursor = num_elements + 1;
+	let mut integer = || {
+		// SLP_TYP is three bits wide, only the low byte of wider encodings matters
+		let (value, size) = match *aml.get(cursor)? {
+			BYTE_PREFIX => (*aml.get(cursor + 1)?, 2),
+			WORD_PREFIX => (*aml.get(cursor + 1)?, 3),
+			DWORD_PREFIX => (*aml.get(cursor + 1)?, 5),
+			QWORD_PREFIX => (*aml.get(cursor + 1)?, 9),
+			ZERO_OP => (0, 1),
+			ONE_OP => (1, 1),
+			_ => return None,
+		};
+		cursor += size;
+		Some(value)
+	};
+
+	let slp_typ_a = integer()?;
+	let slp_typ_b = integer()?;
+	Some((slp_typ_a, slp_typ_b))
+}
+
+fn read_register(register: &GenericAddress) -> u64 {
+	let address = register.address;
+	match register.address_space {
+		AddressSpace::SystemIo => unsafe {
+			match register.bit_width {
+				8 => Port::<u8>::new(address as u16).read() as u64,
+				32 => Port::<u32>::new(address as u16).read() as u64,
+				_ => Port::<u16>::new(address as u16).read() as u64,
+			}
+		},
+		AddressSpace::SystemMemory => unsafe {
+			let ptr = mem::phys_to_virt(PhysAddr::new(address));
+			match register.bit_width {
+				8 => ptr.as_ptr::<u8>().read_volatile() as u64,
+				32 => ptr.as_ptr::<u32>().read_volatile() as u64,
+				64 => ptr.as_ptr::<u64>().read_volatile(),
+				_ => ptr.as_ptr::<u16>().read_volatile() as u64,
+			}
+		},
+		_ => 0,
+	}
+}
+
+fn write_register(register: &GenericAddress, value: u64) {
+	let address = register.address;
+	match register.address_space {
+		AddressSpace::SystemIo => unsafe {
+			match register.bit_width {
+				8 => Port::<u8>::new(address as u16).write(value as u8),
+				32 => Port::<u32>::new(address as u16).write(value as u32),
+				_ => Port::<u16>::new(address as u16).write(value as u16),
+			}
+		},
+		AddressSpace::SystemMemory => unsafe {
+			let ptr = mem::phys_to_virt(PhysAddr::new(address));
+			match register.bit_width {
+				8 => ptr.as_mut_ptr::<u8>().write_volatile(value as u8),
+				32 => ptr.as_mut_ptr::<u32>().write_volatile(value as u32),
+				64 => ptr.as_mut_ptr::<u64>().write_volatile(value),
+				_ => ptr.as_mut_ptr::<u16>().write_volatile(value as u16),
+			}
+		},
+		_ => println!("power: unsupported register address space"),
+	}
+}
+
+/// Gives the hardware a moment to act on a write before trying the next method. Interrupts are
+/// off at this point, so the timer cannot be used
+fn settle() {
+	for _ in 0..10_000_000 {
+		core::hint::spin_loop();
+	}
+}
@@ -0,0 +1,92 @@
+use core::{cell::UnsafeCell, mem::MaybeUninit};
+
+use crate::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// A fixed-size lock-free queue with one producer and one consumer, so an interrupt handler can
+/// hand data to the code that processes it without taking a lock. `N` must be a power of two
+pub struct SpscRing<T: Copy, const N: usize> {
+	slots: [UnsafeCell<MaybeUninit<T>>; N],
+	/// Total pops, only written by the consumer. The slot to read is `head % N`
+	head: AtomicUsize,
+	/// Total pushes, only written by the producer. The slot to write is `tail % N`
+	tail: AtomicUsize,
+	/// Values rejected because the ring was full
+	dropped: AtomicU64,
+}
+
+impl<T: Copy, const N: usize> SpscRing<T, N> {
+	pub const fn new() -> SpscRing<T, N> {
+		assert!(N.is_power_of_two(), "the ring size must be a power of two");
+		SpscRing {
+			slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+			head: AtomicUsize::new(0),
+			tail: AtomicUsize::new(0),
+			dropped: AtomicU64::new(0),
+		}
+	}
+
+	/// Appends `value`, or counts it as dropped and hands it back if the ring is full
+	///
+	/// # Safety
+	///
+	/// Only one core may push at a time, an interrupt handler that is not reentrant qualifies
+	pub unsafe fn push(&self, value: T) -> Result<(), T> {
+		let tail = self.tail.load(Ordering::Relaxed);
+		if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+			self.dropped.fetch_add(1, Ordering::Relaxed);
+			return Err(value);
+		}
+
+		unsafe { (*self.slots[tail % N].get()).write(value) };
+		self.tail.store(tail.wrapping_add(1), Ordering::Release);
+		Ok(())
+	}
+
+	/// Removes the oldest value
+	///
+	/// # Safety
+	///
+	/// Only one core may pop at a time
+	pub unsafe fn pop(&self) -> Option<T> {
+		let head = self.head.load(Ordering::Relaxed);
+		if head == self.tail.load(Ordering::Acquire) {
+			return None;
+		}
+
+		let value = unsafe { (*self.slots[head % N].get()).assume_init_read() };
+		self.head.store(head.wrapping_add(1), Ordering::Release);
+		Some(value)
+	}
+
+	pub fn len(&self) -> usize {
+		let head = self.head.load(Ordering::Relaxed);
+		self.tail.load(Ordering::Relaxed).wrapping_sub(head).min(N)
+	}
+
+	pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+	pub const fn capacity(&self) -> usize { N }
+
+	/// Values accepted since creation, popped or not
+	pub fn pushed(&self) -> u64 { self.tail.load(Ordering::Relaxed) as u64 }
+
+	/// Values lost because the consumer fell behind
+	pub fn dropped(&self) -> u64 { self.dropped.load(Ordering::Relaxed) }
+}
+
+impl<T: Copy, const N: usize> Default for SpscRing<T, N> {
+	fn default() -> Self { Self::new() }
+}
+
+impl<T: Copy, const N: usize> core::fmt::Debug for SpscRing<T, N> {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("SpscRing")
+			.field("len", &self.len())
+			.field("capacity", &N)
+			.field("dropped", &self.dropped())
+			.finish()
+	}
+}
+
+/// The ring only moves copies of `T` between the producer and the consumer
+unsafe impl<T: Copy + Send, const N: usize> Sync for SpscRing<T, N> {}
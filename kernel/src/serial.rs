@@ -1,7 +1,8 @@
 use uart_16550::SerialPort;
 
-use crate::mutex::Mutex;
-use crate::once_lock::OnceLock;
+use crate::mutex::{IrqMutex, LockStats};
+use crate::once_lock::Lazy;
+use crate::percpu::{self, Console};
 
 pub struct Port {
 	base: usize,
@@ -24,23 +25,36 @@ impl core::ops::DerefMut for Port {
 	fn deref_mut(&mut self) -> &mut Self::Target { &mut self.inner }
 }
 
-pub static SERIAL1: OnceLock<Mutex<Port>> = OnceLock::new();
+pub static SERIAL1: Lazy<IrqMutex<Port>> = Lazy::new(serial_init);
+static SERIAL1_STATS: LockStats = LockStats::new("SERIAL1");
 
-pub fn serial_init() -> Mutex<Port> {
-	let mut serial_port = unsafe { uart_16550::SerialPort::new(0x3F8) };
+/// I/O port base of COM1, behind [`SERIAL1`]
+const COM1: u16 = 0x3F8;
+
+fn serial_init() -> IrqMutex<Port> {
+	let mut serial_port = unsafe { uart_16550::SerialPort::new(COM1) };
 	serial_port.init();
-	Mutex::new(Port { inner: serial_port, base: 0x3F8 })
+	IrqMutex::with_stats(Port { inner: serial_port, base: COM1 as usize }, &SERIAL1_STATS)
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
 	use core::fmt::Write;
 
-	use x86_64::instructions::interrupts;
+	let Some(_printing) = percpu::start_printing(Console::Serial) else {
+		_print_raw(args);
+		return;
+	};
+	SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+}
+
+/// Writes to COM1 without taking [`SERIAL1`], for an NMI or a machine check that interrupted
+/// this core while it held the lock. The output may land in the middle of the interrupted line
+#[doc(hidden)]
+pub fn _print_raw(args: ::core::fmt::Arguments) {
+	use core::fmt::Write;
 
-	interrupts::without_interrupts(|| {
-		SERIAL1.get().unwrap().lock().write_fmt(args).expect("Printing to serial failed");
-	});
+	let _ = unsafe { SerialPort::new(COM1) }.write_fmt(args);
 }
 
 /// Prints to the host through the serial interface.
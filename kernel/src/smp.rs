@@ -0,0 +1,386 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{
+	arch::global_asm,
+	ptr::addr_of,
+	sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use acpi::{platform::ProcessorState, InterruptModel};
+use x86_64::{
+	registers::control::Cr3,
+	structures::paging::{
+		FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+	},
+	VirtAddr,
+};
+
+use crate::{
+	acpi_tables, apic, cpu, fpu, gdt,
+	interrupts::{self, InterruptIndex},
+	ipi, mce, mem,
+	once_lock::OnceLock,
+	percpu, println, syscall, thread, time, watchdog,
+};
+
+pub const MAX_CPUS: usize = 16;
+const AP_STACK_SIZE: usize = 4096 * 4;
+/// SIPI vectors are 8 bits wide, so the trampoline must start below 1 MiB
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+/// Frames [`allocate_trampoline`] tries before giving up on SMP
+const TRAMPOLINE_ATTEMPTS: usize = 8;
+
+/// Processors listed in the MADT, the bootstrap processor is always the first one
+pub static CPUS: OnceLock<Vec<Cpu>> = OnceLock::new();
+
+static AP_STATE: [ApState; MAX_CPUS] = [const { ApState::new() }; MAX_CPUS];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cpu {
+	pub uid: u32,
+	pub apic_id: u32,
+	pub is_bsp: bool,
+}
+
+/// Mailbox of an application processor, `work` holds a `fn()` the core runs once, or zero
+#[derive(Debug)]
+struct ApState {
+	online: AtomicBool,
+	work: AtomicUsize,
+}
+
+impl ApState {
+	const fn new() -> ApState {
+		ApState { online: AtomicBool::new(false), work: AtomicUsize::new(0) }
+	}
+}
+
+/// Parameters the BSP patches into the trampoline copy before starting each AP
+#[repr(C)]
+struct TrampolineArgs {
+	cr3: u64,
+	stack: u64,
+	entry: u64,
+	cpu: u64,
+}
+
+extern "C" {
+	static ap_trampoline_start: u8;
+	static ap_trampoline_end: u8;
+	static ap_args: u8;
+}
+
+// Real mode entry point of the application processors. The code is copied to a page below
+// 1 MiB, where the SIPI starts it with CS pointing at the page, and jumps straight to long mode
+// using the kernel page tables, which must identity map the page.
+global_asm!(
+	r#"
+.pushsection .text.ap_trampoline, "ax"
+.global ap_trampoline_start
+.global ap_trampoline_end
+.global ap_args
+.code16
+ap_trampoline_start:
+	cli
+	cld
+	mov %cs, %ax
+	mov %ax, %ds
+
+	/* Linear address of the trampoline, to patch the GDT pointer and the far jump */
+	xor %ebx, %ebx
+	mov %cs, %bx
+	shl $4, %ebx
+	mov %ebx, %eax
+	add $(ap_gdt - ap_trampoline_start), %eax
+	mov %eax, (ap_gdt_ptr - ap_trampoline_start + 2)
+	mov %ebx, %eax
+	add $(ap_long_mode - ap_trampoline_start), %eax
+	mov %eax, (ap_far_ptr - ap_trampoline_start)
+
+	/* PAE | PGE */
+	mov %cr4, %eax
+	or $0xA0, %eax
+	mov %eax, %cr4
+	mov (ap_args - ap_trampoline_start), %eax
+	mov %eax, %cr3
+
+	/* EFER.LME | EFER.NXE, the kernel tables use the NX bit */
+	mov $0xC0000080, %ecx
+	rdmsr
+	or $0x900, %eax
+	wrmsr
+
+	lgdtl (ap_gdt_ptr - ap_trampoline_start)
+
+	/* PG | WP | PE */
+	mov %cr0, %eax
+	or $0x80010001, %eax
+	mov %eax, %cr0
+	ljmpl *(ap_far_ptr - ap_trampoline_start)
+
+.code64
+ap_long_mode:
+	mov $0x10, %ax
+	mov %ax, %ds
+	mov %ax, %es
+	mov %ax, %ss
+	xor %ax, %ax
+	mov %ax, %fs
+	mov %ax, %gs
+	mov (ap_args + 8)(%rip), %rsp
+	mov (ap_args + 24)(%rip), %rdi
+	mov (ap_args + 16)(%rip), %rax
+	call *%rax
+	ud2
+
+.balign 16
+ap_gdt:
+	.quad 0
+	.quad 0x00af9a000000ffff
+	.quad 0x00cf92000000ffff
+ap_gdt_ptr:
+	.word ap_gdt_ptr - ap_gdt - 1
+	.long 0
+ap_far_ptr:
+	.long 0
+	.word 0x08
+.balign 8
+ap_args:
+	.fill 4, 8, 0
+ap_trampoline_end:
+.popsection
+"#,
+	options(att_syntax)
+);
+
+/// Discovers the processors in the MADT and starts every application processor, `trampoline`
+/// must be an unused frame from [`allocate_trampoline`]
+pub fn init(
+	mapper: &mut impl Mapper<Size4KiB>,
+	frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+	trampoline: Option<PhysFrame>,
+) {
+	AP_STATE[0].online.store(true, Ordering::Release);
+	if !cpu::features().apic {
+		println!("SMP: no local APIC, running on the BSP only");
+		return;
+	}
+
+	let Some((lapic_base, cpus)) = discover() else {
+		println!("SMP: no MADT, running on the BSP only");
+		return;
+	};
+
+	let _ = apic::LAPIC.set(unsafe { apic::LocalApic::new(lapic_base) });
+	let lapic = apic::LAPIC.get().unwrap();
+	lapic.enable();
+	let cpus = CPUS.get_or_init(|| cpus);
+	for (index, cpu) in cpus.iter().enumerate() {
+		percpu::get(index).set_apic_id(cpu.apic_id);
+	}
+
+	let Some(trampoline) = trampoline.filter(|&frame| can_hold_trampoline(frame)) else {
+		println!("SMP: no frame between 4 KiB and 1 MiB for the trampoline");
+		return;
+	};
+
+	let page =
+		Page::<Size4KiB>::containing_address(VirtAddr::new(trampoline.start_address().as_u64()));
+	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+	// Firmware or bootloader may already identity map low memory, leave such mappings alone
+	let mapped = unsafe { mapper.identity_map(trampoline, flags, frame_allocator) }
+		.map(|flush| flush.flush())
+		.is_ok();
+
+	copy_trampoline(trampoline);
+	for (index, cpu) in cpus.iter().enumerate().skip(1) {
+		if start_ap(lapic, trampoline, index, cpu) {
+			println!("SMP: CPU {} (APIC {}) online", index, cpu.apic_id);
+		} else {
+			println!("SMP: CPU {} (APIC {}) did not start", index, cpu.apic_id);
+		}
+	}
+
+	// The APs went through the identity mapping too, so their TLBs must forget it as well
+	if mapped {
+		let _ = mem::unmap_page(mapper, page);
+	}
+}
+
+fn discover() -> Option<(u64, Vec<Cpu>)> {
+	let tables = acpi_tables::tables()?;
+	let info = tables.platform_info().ok()?;
+	let InterruptModel::Apic(apic) = info.interrupt_model else { return None };
+	let processors = info.processor_info?;
+
+	let bsp = processors.boot_processor;
+	let mut cpus = vec![Cpu { uid: bsp.processor_uid, apic_id: bsp.local_apic_id, is_bsp: true }];
+	cpus.extend(
+		processors
+			.application_processors
+			.iter()
+			.filter(|p| !matches!(p.state, ProcessorState::Disabled))
+			.map(|p| Cpu { uid: p.processor_uid, apic_id: p.local_apic_id, is_bsp: false })
+			.take(MAX_CPUS - 1),
+	);
+	Some((apic.local_apic_address, cpus))
+}
+
+/// Takes a frame for the trampoline from the first ones `frame_allocator` hands out, which are
+/// the low ones. The frames it passes over are lost
+pub fn allocate_trampoline(
+	frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Option<PhysFrame> {
+	core::iter::from_fn(|| frame_allocator.allocate_frame())
+		.take(TRAMPOLINE_ATTEMPTS)
+		.find(|&frame| can_hold_trampoline(frame))
+}
+
+/// Whether the SIPI can start an AP in `frame`. Frame 0 is out, it holds the real mode
+/// interrupt vector table and would make a null start address
+fn can_hold_trampoline(frame: PhysFrame) -> bool {
+	let start = frame.start_address().as_u64();
+	start != 0 && start < TRAMPOLINE_LIMIT
+}
+
+fn trampoline_offset(symbol: *const u8) -> usize {
+	symbol as usize - unsafe { addr_of!(ap_trampoline_start) } as usize
+}
+
+fn copy_trampoline(frame: PhysFrame) {
+	let len = trampoline_offset(unsafe { addr_of!(ap_trampoline_end) });
+	assert!(len <= Size4KiB::SIZE as usize, "AP trampoline does not fit in a page");
+
+	let dst = mem::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+	unsafe { core::ptr::copy_nonoverlapping(addr_of!(ap_trampoline_start), dst, len) };
+}
+
+/// Runs the INIT-SIPI-SIPI sequence for one AP and waits for it to report itself online
+fn start_ap(lapic: &apic::LocalApic, trampoline: PhysFrame, index: usize, cpu: &Cpu) -> bool {
+	let (cr3, _) = Cr3::read();
+	assert!(cr3.start_address().as_u64() < u32::MAX as u64, "CR3 unreachable from real mode");
+
+	let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+	let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xF;
+
+	let args = mem::phys_to_virt(trampoline.start_address())
+		+ trampoline_offset(unsafe { addr_of!(ap_args) }) as u64;
+	unsafe {
+		args.as_mut_ptr::<TrampolineArgs>().write_volatile(TrampolineArgs {
+			cr3: cr3.start_address().as_u64(),
+			stack: stack_top,
+			entry: ap_main as usize as u64,
+			cpu: index as u64,
+		})
+	};
+
+	let page = (trampoline.start_address().as_u64() >> 12) as u8;
+	lapic.send_init(cpu.apic_id);
+	time::sleep_ms(10);
+	for _ in 0..2 {
+		lapic.send_startup(cpu.apic_id, page);
+		time::sleep_ms(1);
+		if is_online(index) {
+			return true;
+		}
+	}
+
+	let deadline = time::ticks() + 100 * time::TIMER_HZ / 1000;
+	while time::ticks() < deadline {
+		if is_online(index) {
+			return true;
+		}
+		x86_64::instructions::hlt();
+	}
+	false
+}
+
+extern "C" fn ap_main(cpu: u64) -> ! {
+	let cpu = cpu as usize;
+	gdt::init_cpu(percpu::init(cpu), gdt::init_ap_tss());
+	syscall::init();
+	fpu::init();
+	mce::init();
+	interrupts::load_idt();
+	apic::LAPIC.get().unwrap().enable();
+	thread::init_cpu();
+
+	AP_STATE[cpu].online.store(true, Ordering::Release);
+	x86_64::instructions::interrupts::enable();
+	idle_loop(cpu)
+}
+
+/// Parks an AP until [`run_on`] hands it some work and wakes it up, or threads are ready in its
+/// run queue. This is the idle thread of the core
+fn idle_loop(cpu: usize) -> ! {
+	use x86_64::instructions::interrupts;
+
+	loop {
+		// Checking with interrupts off closes the window where the wakeup IPI arrives right
+		// before the `hlt`
+		interrupts::disable();
+		watchdog::touch();
+		let work = AP_STATE[cpu].work.swap(0, Ordering::Acquire);
+		if work == 0 && thread::has_ready() {
+			interrupts::enable();
+			thread::yield_now();
+			continue;
+		}
+		if work == 0 {
+			interrupts::enable_and_hlt();
+			continue;
+		}
+
+		interrupts::enable();
+		let work = unsafe { core::mem::transmute::<usize, fn()>(work) };
+		work();
+	}
+}
+
+pub fn is_online(cpu: usize) -> bool {
+	AP_STATE.get(cpu).is_some_and(|state| state.online.load(Ordering::Acquire))
+}
+
+pub fn online_cpus() -> usize { (0..MAX_CPUS).filter(|&cpu| is_online(cpu)).count() }
+
+/// Parks every AP for good with interrupts disabled, waiting a moment for them to go offline.
+///
+/// Each core is taken out of scheduling first: its queued threads move to the boot processor
+/// and a reschedule IPI preempts the running one, so the idle loop gets to park the core
+pub fn stop_aps() {
+	fn park() {
+		let cpu = percpu::current().id();
+		// Offline before interrupts go off, remote calls pick their targets by it. A call that
+		// still saw the core online is run below, or claimed by its caller
+		AP_STATE[cpu].online.store(false, Ordering::Release);
+		x86_64::instructions::interrupts::disable();
+		// Threads queued while the core was still on its way here
+		thread::stop_cpu(cpu);
+		ipi::handle_pending(cpu);
+		crate::hlt_loop()
+	}
+
+	for cpu in (1..MAX_CPUS).filter(|&cpu| is_online(cpu)) {
+		thread::stop_cpu(cpu);
+		let _ = run_on(cpu, park);
+	}
+
+	let deadline = time::ticks() + 100 * time::TIMER_HZ / 1000;
+	while (1..MAX_CPUS).any(is_online) && time::ticks() < deadline {
+		core::hint::spin_loop();
+	}
+}
+
+/// Asks the idle AP `cpu` to run `work`. Fails if the core is offline, is the BSP or still has
+/// work pending
+pub fn run_on(cpu: usize, work: fn()) -> Result<(), fn()> {
+	if cpu == 0 || !is_online(cpu) {
+		return Err(work);
+	}
+
+	AP_STATE[cpu]
+		.work
+		.compare_exchange(0, work as usize, Ordering::Release, Ordering::Relaxed)
+		.map_err(|_| work)?;
+	ipi::send(ipi::Target::Cpu(cpu), InterruptIndex::Wakeup as u8);
+	Ok(())
+}
@@ -0,0 +1,85 @@
+use crate::once_lock::OnceLock;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 20;
+
+static TABLE: OnceLock<SymbolTable> = OnceLock::new();
+
+/// Symbol table the build script stores in the ramdisk, see `write_symbol_table` in the
+/// top-level `build.rs` for the layout
+#[derive(Debug)]
+struct SymbolTable {
+	data: &'static [u8],
+	count: usize,
+	/// Where the bootloader placed the kernel relative to its link address
+	image_offset: u64,
+}
+
+/// Link-time address, size and name of one function
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+	address: u64,
+	size: u64,
+	name: &'static str,
+}
+
+impl SymbolTable {
+	fn entry(&self, index: usize) -> Entry {
+		let at = HEADER_SIZE + index * ENTRY_SIZE;
+		let field = |start: usize, len: usize| {
+			let mut bytes = [0; 8];
+			bytes[..len].copy_from_slice(&self.data[at + start..at + start + len]);
+			u64::from_le_bytes(bytes)
+		};
+
+		let names = HEADER_SIZE + self.count * ENTRY_SIZE;
+		let name_start = names + field(12, 4) as usize;
+		let name_end = name_start + field(16, 4) as usize;
+		let name = self
+			.data
+			.get(name_start..name_end)
+			.and_then(|name| core::str::from_utf8(name).ok())
+			.unwrap_or("<invalid>");
+		Entry { address: field(0, 8), size: field(8, 4), name }
+	}
+
+	/// Binary search for the last symbol starting at or before `address`
+	fn lookup(&self, address: u64) -> Option<Entry> {
+		let (mut low, mut high) = (0, self.count);
+		while low < high {
+			let mid = low + (high - low) / 2;
+			if self.entry(mid).address <= address {
+				low = mid + 1;
+			} else {
+				high = mid;
+			}
+		}
+
+		let entry = self.entry(low.checked_sub(1)?);
+		(entry.size == 0 || address < entry.address + entry.size).then_some(entry)
+	}
+}
+
+/// Loads the table from the ramdisk the bootloader mapped, `image_offset` is the
+/// `kernel_image_offset` of the boot info. Returns whether the ramdisk held a valid table
+pub fn init(ramdisk: &'static [u8], image_offset: u64) -> bool {
+	if ramdisk.len() < HEADER_SIZE || &ramdisk[..4] != MAGIC {
+		return false;
+	}
+
+	let count = u32::from_le_bytes(ramdisk[4..8].try_into().unwrap()) as usize;
+	if ramdisk.len() < HEADER_SIZE + count * ENTRY_SIZE {
+		return false;
+	}
+
+	TABLE.set(SymbolTable { data: ramdisk, count, image_offset }).is_ok()
+}
+
+/// Resolves a runtime address to the demangled name of its function and the offset into it
+pub fn resolve(address: u64) -> Option<(&'static str, u64)> {
+	let table = TABLE.get()?;
+	let link_address = address.checked_sub(table.image_offset)?;
+	let entry = table.lookup(link_address)?;
+	Some((entry.name, link_address - entry.address))
+}
@@ -0,0 +1,7 @@
+// Everything `mutex`, `once_lock` and `ring` build on besides plain `core`. The host tests in
+// `sync-tests` swap this module for one that runs them under a model checker, so these types
+// must not reach for atomics, spinning or interrupt control anywhere else
+
+pub use core::{hint::spin_loop, sync::atomic};
+
+pub use x86_64::instructions::interrupts;
@@ -0,0 +1,222 @@
+// System calls from ring 3. `syscall` is the fast way in, `int 0x80` reaches the same table and
+// also works from the kernel, for debugging. The ABI follows Linux: the number goes in rax, up
+// to six arguments in rdi, rsi, rdx, r10, r8 and r9, and the result comes back in rax, a negated
+// `Errno` on failure. Every other register is preserved, except rcx and r11 which `syscall`
+// itself overwrites
+
+use core::{arch::global_asm, mem::offset_of};
+
+use x86_64::{
+	registers::{
+		model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+		rflags::RFlags,
+	},
+	VirtAddr,
+};
+
+use crate::{gdt, mem, percpu::PerCpu, print, thread, time};
+
+/// Numbers of the system calls, part of the ABI so never reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Syscall {
+	/// `exit()`, ends the calling thread
+	Exit     = 0,
+	/// `write(fd, buf, len)`, prints UTF-8 text to the console for `fd` 1 and 2. Returns `len`
+	Write    = 1,
+	/// `yield()`, see [`thread::yield_now`]
+	Yield    = 2,
+	/// `sleep(ms)`
+	Sleep    = 3,
+	/// `thread_id()`, the id of the calling thread
+	ThreadId = 4,
+	/// `uptime()`, milliseconds since boot
+	Uptime   = 5,
+}
+
+/// Errors of the system calls, with the values Linux uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+	/// The file descriptor does not exist
+	EBADF  = 9,
+	/// A buffer is not accessible to ring 3
+	EFAULT = 14,
+	EINVAL = 22,
+	/// There is no system call with that number
+	ENOSYS = 38,
+}
+
+type Handler = fn(&[u64; 6]) -> Result<u64, Errno>;
+
+/// Indexed by [`Syscall`]
+const TABLE: [Handler; 6] = [sys_exit, sys_write, sys_yield, sys_sleep, sys_thread_id, sys_uptime];
+
+/// Registers pushed by both entries, lowest address first
+#[repr(C)]
+struct Frame {
+	number: u64,
+	/// rdi, rsi, rdx, r10, r8 and r9
+	args: [u64; 6],
+}
+
+extern "C" {
+	fn syscall_entry();
+	fn syscall_int80();
+}
+
+// `syscall` leaves the user stack in place, so the entry swaps in the kernel GS base to find the
+// kernel stack of the thread, and moves the user stack pointer over to it before interrupts are
+// enabled. The thread may be preempted and resumed on another core from there on.
+//
+// The `int 0x80` entry already runs on the kernel stack the TSS points to, and only swaps the GS
+// base if it came from ring 3. Both restore every register the dispatcher may clobber on the
+// way out, no kernel value leaks to ring 3.
+global_asm!(
+	r#"
+.global syscall_entry
+.global syscall_int80
+
+syscall_entry:
+	swapgs
+	mov gs:[{user_stack}], rsp
+	mov rsp, gs:[{kernel_stack}]
+	push qword ptr gs:[{user_stack}]
+	push r11
+	push rcx
+	push r9
+	push r8
+	push r10
+	push rdx
+	push rsi
+	push rdi
+	push rax
+	mov rdi, rsp
+	sti
+	call {dispatch}
+	cli
+	add rsp, 8
+	pop rdi
+	pop rsi
+	pop rdx
+	pop r10
+	pop r8
+	pop r9
+	pop rcx
+	pop r11
+	pop rsp
+	swapgs
+	sysretq
+
+syscall_int80:
+	test byte ptr [rsp + 8], 3
+	jz 1f
+	swapgs
+1:
+	push r11
+	push rcx
+	push r9
+	push r8
+	push r10
+	push rdx
+	push rsi
+	push rdi
+	push rax
+	mov rdi, rsp
+	test dword ptr [rsp + 9 * 8 + 16], 0x200
+	jz 2f
+	sti
+2:
+	call {dispatch}
+	cli
+	add rsp, 8
+	pop rdi
+	pop rsi
+	pop rdx
+	pop r10
+	pop r8
+	pop r9
+	pop rcx
+	pop r11
+	test byte ptr [rsp + 8], 3
+	jz 3f
+	swapgs
+3:
+	iretq
+"#,
+	user_stack = const offset_of!(PerCpu, user_stack),
+	kernel_stack = const offset_of!(PerCpu, kernel_stack),
+	dispatch = sym syscall_dispatch,
+);
+
+/// Enables `syscall` on the calling core. Its GDT must be loaded already
+pub fn init() {
+	let selectors = gdt::selectors();
+	Star::write(
+		selectors.user_code(),
+		selectors.user_data(),
+		selectors.kernel_code(),
+		selectors.kernel_data(),
+	)
+	.expect("GDT layout does not suit sysret");
+	LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+	// Entered with interrupts off until the kernel stack is loaded, and the flags Rust expects.
+	// `thread_switch` keeps RFLAGS as they are, so NT and IOPL set by ring 3 must not reach a
+	// thread that returns from an interrupt with `iretq`
+	SFMask::write(
+		RFlags::INTERRUPT_FLAG
+			| RFlags::TRAP_FLAG
+			| RFlags::DIRECTION_FLAG
+			| RFlags::ALIGNMENT_CHECK
+			| RFlags::NESTED_TASK
+			| RFlags::IOPL_HIGH
+			| RFlags::IOPL_LOW,
+	);
+	unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Address of the `int 0x80` entry, for the IDT
+pub fn int80_entry() -> VirtAddr { VirtAddr::new(syscall_int80 as *const () as u64) }
+
+extern "C" fn syscall_dispatch(frame: &Frame) -> i64 {
+	let result = TABLE
+		.get(frame.number as usize)
+		.ok_or(Errno::ENOSYS)
+		.and_then(|handler| handler(&frame.args));
+	match result {
+		Ok(value) => value as i64,
+		Err(errno) => -(errno as i64),
+	}
+}
+
+fn sys_exit(_args: &[u64; 6]) -> Result<u64, Errno> { thread::exit() }
+
+fn sys_write(args: &[u64; 6]) -> Result<u64, Errno> {
+	let [fd, buf, len, ..] = *args;
+	if fd != 1 && fd != 2 {
+		return Err(Errno::EBADF);
+	}
+	let buf = VirtAddr::try_new(buf).map_err(|_| Errno::EFAULT)?;
+	if !mem::user_accessible(buf, len as usize, false) {
+		return Err(Errno::EFAULT);
+	}
+
+	let bytes = unsafe { core::slice::from_raw_parts(buf.as_ptr::<u8>(), len as usize) };
+	let text = core::str::from_utf8(bytes).map_err(|_| Errno::EINVAL)?;
+	print!("{text}");
+	Ok(len)
+}
+
+fn sys_yield(_args: &[u64; 6]) -> Result<u64, Errno> {
+	thread::yield_now();
+	Ok(0)
+}
+
+fn sys_sleep(args: &[u64; 6]) -> Result<u64, Errno> {
+	thread::sleep(args[0]);
+	Ok(0)
+}
+
+fn sys_thread_id(_args: &[u64; 6]) -> Result<u64, Errno> { Ok(thread::current().as_usize() as u64) }
+
+fn sys_uptime(_args: &[u64; 6]) -> Result<u64, Errno> { Ok(time::uptime_ms()) }
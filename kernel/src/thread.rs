@@ -0,0 +1,765 @@
+use alloc::{
+	boxed::Box,
+	collections::{BTreeMap, VecDeque},
+	format, vec,
+};
+use core::{
+	arch::global_asm,
+	cell::UnsafeCell,
+	future::Future,
+	pin::Pin,
+	ptr::NonNull,
+	sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+	task::{Context, RawWaker, RawWakerVTable, Waker},
+};
+
+use x86_64::{instructions::interrupts, VirtAddr};
+
+use crate::{
+	fpu::FpuState, gdt, interrupts::InterruptIndex, ipi, mutex::IrqMutex, percpu, println,
+	smp::MAX_CPUS, time, watchdog,
+};
+
+/// Kernel stack of every spawned thread. There is no guard page below it
+const STACK_SIZE: usize = 4096 * 4;
+/// Ticks a thread may run while others of its priority wait
+const TIME_SLICE: u64 = 10 * time::TIMER_HZ / 1000;
+/// Ticks between two rounds of moving threads from busy cores to idle ones
+const BALANCE_INTERVAL: u64 = 100 * time::TIMER_HZ / 1000;
+const PRIORITIES: usize = 4;
+
+/// Every thread that did not exit, plus the exited ones not reaped yet. Taken before any run
+/// queue
+static THREADS: IrqMutex<BTreeMap<ThreadId, Box<Thread>>> = IrqMutex::new(BTreeMap::new());
+static RUN_QUEUES: [IrqMutex<RunQueue>; MAX_CPUS] =
+	[const { IrqMutex::new(RunQueue::new()) }; MAX_CPUS];
+/// Cores with a run queue and an idle thread, bit `n` for CPU `n`
+static ACTIVE: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+	fn thread_switch(save_rsp: *mut usize, rsp: usize, on_cpu: *const AtomicBool);
+	fn thread_trampoline();
+}
+
+// Saves the callee-saved registers of the calling thread on its stack, stores its stack pointer
+// and resumes the thread whose stack pointer is passed. The outgoing thread's `on_cpu` is
+// cleared only once nothing is left to save, so another core may pick it up right after.
+//
+// A new thread starts by returning into `thread_trampoline`, with its entry point in r12.
+global_asm!(
+	r#"
+.global thread_switch
+.global thread_trampoline
+
+thread_switch:
+	push rbp
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+	mov [rdi], rsp
+	mov byte ptr [rdx], 0
+	mov rsp, rsi
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	pop rbp
+	ret
+
+thread_trampoline:
+	mov rdi, r12
+	call {entry}
+	ud2
+"#,
+	entry = sym thread_entry,
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(usize);
+
+impl ThreadId {
+	/// The thread that booted the kernel, it runs `kernel_main` and never exits
+	pub const BOOT: ThreadId = ThreadId(0);
+
+	fn new() -> ThreadId {
+		static NEXT: AtomicUsize = AtomicUsize::new(1);
+		ThreadId(NEXT.fetch_add(1, Ordering::Relaxed))
+	}
+
+	pub fn as_usize(self) -> usize { self.0 }
+}
+
+/// Ready threads of a higher priority always run first, those of the same one take turns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+	/// Only runs when a core has nothing else to do, like its idle thread
+	Idle,
+	Low,
+	Normal,
+	High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum State {
+	Ready,
+	Running,
+	/// Parked until [`unpark`]
+	Blocked,
+	/// Exited, freed by the next [`spawn`]
+	Dead,
+}
+
+impl State {
+	fn from_u8(state: u8) -> State {
+		match state {
+			0 => State::Ready,
+			1 => State::Running,
+			2 => State::Blocked,
+			_ => State::Dead,
+		}
+	}
+}
+
+struct Thread {
+	id: ThreadId,
+	name: &'static str,
+	priority: Priority,
+	/// Cores the thread may run on, bit `n` for CPU `n`
+	affinity: AtomicU64,
+	state: AtomicU8,
+	/// Core running the thread or holding it in its run queue
+	cpu: AtomicUsize,
+	/// Ticks spent running, up to the last switch away from it
+	cpu_time: AtomicU64,
+	/// Tick at which the thread last got a core
+	switched_in: AtomicU64,
+	/// Set by [`unpark`] until the thread parks, so a wakeup coming first is not lost
+	unparked: AtomicBool,
+	/// Stack pointer saved by `thread_switch` while the thread is not running
+	rsp: UnsafeCell<usize>,
+	/// Set while a core is on the thread's stack, only that core touches `rsp` and `fpu`
+	on_cpu: AtomicBool,
+	fpu: UnsafeCell<FpuState>,
+	/// `None` for the threads that kept the stack they booted on
+	stack: Option<Box<[u8]>>,
+}
+
+impl Thread {
+	fn new(id: ThreadId, name: &'static str, priority: Priority, affinity: u64) -> Thread {
+		Thread {
+			id,
+			name,
+			priority,
+			affinity: AtomicU64::new(affinity),
+			state: AtomicU8::new(State::Ready as u8),
+			cpu: AtomicUsize::new(percpu::current().id()),
+			cpu_time: AtomicU64::new(0),
+			switched_in: AtomicU64::new(0),
+			unparked: AtomicBool::new(false),
+			rsp: UnsafeCell::new(0),
+			on_cpu: AtomicBool::new(false),
+			fpu: UnsafeCell::new(FpuState::new()),
+			stack: None,
+		}
+	}
+
+	/// Turns the code running on the calling core into the thread
+	fn running_here(self) -> Thread {
+		self.set_state(State::Running);
+		self.switched_in.store(time::ticks(), Ordering::Relaxed);
+		self.on_cpu.store(true, Ordering::Relaxed);
+		self
+	}
+
+	/// Gives the thread a stack of its own on which it starts running `entry`
+	fn with_entry(mut self, entry: fn()) -> Thread {
+		self.stack = Some(vec![0u8; STACK_SIZE].into_boxed_slice());
+		let top = self.stack_top().unwrap().as_u64() as usize;
+		// Popped by the first `thread_switch` to the thread: r15, r14, r13, r12, rbx, rbp and the
+		// return address. The null rbp ends backtraces
+		let frame = [0, 0, 0, entry as usize, 0, 0, thread_trampoline as *const () as usize];
+		let rsp = top - core::mem::size_of_val(&frame);
+		unsafe { (rsp as *mut [usize; 7]).write(frame) };
+
+		self.rsp = UnsafeCell::new(rsp);
+		self
+	}
+
+	/// Highest 16 byte aligned address of the thread's own stack
+	fn stack_top(&self) -> Option<VirtAddr> {
+		let stack = self.stack.as_ref()?;
+		Some(VirtAddr::from_ptr(stack.as_ptr_range().end).align_down(16u64))
+	}
+
+	fn state(&self) -> State { State::from_u8(self.state.load(Ordering::Relaxed)) }
+
+	fn set_state(&self, state: State) { self.state.store(state as u8, Ordering::Relaxed) }
+
+	/// Marks the running thread blocked unless an [`unpark`] came in since it last parked, and
+	/// returns whether it did. Either this sees the wakeup or `unpark` sees the thread blocked
+	fn block(&self) -> bool {
+		self.state.store(State::Blocked as u8, Ordering::SeqCst);
+		if !self.unparked.swap(false, Ordering::SeqCst) {
+			return true;
+		}
+
+		// `unpark` may have seen the state as well, then it queues the thread and it leaves
+		let running = State::Running as u8;
+		let blocked = State::Blocked as u8;
+		self.state.compare_exchange(blocked, running, Ordering::SeqCst, Ordering::Relaxed).is_err()
+	}
+
+	fn allowed(&self, cpu: usize) -> bool {
+		self.affinity.load(Ordering::Relaxed) & (1 << cpu) != 0
+	}
+
+	/// Ticks spent running, including the current stint
+	fn cpu_time(&self) -> u64 {
+		let mut ticks = self.cpu_time.load(Ordering::Relaxed);
+		if self.state() == State::Running {
+			ticks += time::ticks().saturating_sub(self.switched_in.load(Ordering::Relaxed));
+		}
+		ticks
+	}
+}
+
+/// Pointer to a [`Thread`] owned by [`THREADS`]. Threads are only freed once dead and off every
+/// core, when no run queue refers to them anymore
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct ThreadRef(NonNull<Thread>);
+
+impl ThreadRef {
+	fn new(thread: &Thread) -> ThreadRef { ThreadRef(NonNull::from(thread)) }
+}
+
+impl core::ops::Deref for ThreadRef {
+	type Target = Thread;
+
+	fn deref(&self) -> &Thread { unsafe { self.0.as_ref() } }
+}
+
+unsafe impl Send for ThreadRef {}
+
+struct RunQueue {
+	/// Threads waiting for the core, one queue per priority. [`Builder::spawn`] keeps room for
+	/// every thread in each, so interrupt handlers can queue threads without allocating
+	ready: [VecDeque<ThreadRef>; PRIORITIES],
+	/// `None` until the core registered its threads
+	current: Option<ThreadRef>,
+	/// Runs whenever no other thread is ready, it is never queued
+	idle: Option<ThreadRef>,
+}
+
+impl RunQueue {
+	const fn new() -> RunQueue {
+		RunQueue { ready: [const { VecDeque::new() }; PRIORITIES], current: None, idle: None }
+	}
+
+	fn push(&mut self, thread: ThreadRef) {
+		thread.set_state(State::Ready);
+		self.ready[thread.priority as usize].push_back(thread);
+	}
+
+	/// Takes the first thread of the highest priority that is at least `min`
+	fn pop(&mut self, min: Priority) -> Option<ThreadRef> {
+		self.ready[min as usize..].iter_mut().rev().find_map(VecDeque::pop_front)
+	}
+
+	fn remove(&mut self, thread: ThreadRef) -> bool {
+		let queue = &mut self.ready[thread.priority as usize];
+		let index = queue.iter().position(|&queued| queued == thread);
+		index.and_then(|index| queue.remove(index)).is_some()
+	}
+
+	/// Takes the most recently queued thread of the highest priority that may run on `cpu`
+	fn steal(&mut self, cpu: usize) -> Option<ThreadRef> {
+		self.ready.iter_mut().rev().find_map(|queue| {
+			let index = queue.iter().rposition(|thread| thread.allowed(cpu))?;
+			queue.remove(index)
+		})
+	}
+
+	fn queued(&self) -> usize { self.ready.iter().map(VecDeque::len).sum() }
+
+	/// Threads that want the core, the running one included unless it is the idle thread
+	fn load(&self) -> usize { self.queued() + usize::from(self.current != self.idle) }
+
+	fn reserve(&mut self, threads: usize) {
+		for queue in &mut self.ready {
+			queue.reserve(threads.saturating_sub(queue.len()));
+		}
+	}
+
+	/// Whether the running thread should give the core to a ready one
+	fn should_preempt(&self) -> bool {
+		let Some(current) = self.current else { return false };
+		let Some(best) = (0..PRIORITIES).rev().find(|&p| !self.ready[p].is_empty()) else {
+			return false;
+		};
+
+		let ran = time::ticks().saturating_sub(current.switched_in.load(Ordering::Relaxed));
+		Some(current) == self.idle
+			|| best > current.priority as usize
+			|| (best == current.priority as usize && ran >= TIME_SLICE)
+	}
+}
+
+/// Configures a thread before [`Builder::spawn`] starts it
+#[derive(Debug, Clone)]
+pub struct Builder {
+	name: &'static str,
+	priority: Priority,
+	affinity: u64,
+}
+
+impl Builder {
+	pub fn new() -> Builder {
+		Builder { name: "kthread", priority: Priority::Normal, affinity: u64::MAX }
+	}
+
+	/// Name shown by [`dump`]
+	pub fn name(mut self, name: &'static str) -> Builder {
+		self.name = name;
+		self
+	}
+
+	pub fn priority(mut self, priority: Priority) -> Builder {
+		self.priority = priority;
+		self
+	}
+
+	/// Restricts the thread to the cores whose bit is set, bit `n` for CPU `n`
+	pub fn affinity(mut self, cpus: u64) -> Builder {
+		self.affinity = cpus;
+		self
+	}
+
+	/// Starts a thread running `entry` on a stack of its own, on the least loaded core it may
+	/// run on. It exits once `entry` returns
+	pub fn spawn(self, entry: fn()) -> ThreadId {
+		assert!(self.affinity & ACTIVE.load(Ordering::Acquire) != 0, "no core to run on");
+
+		let id = ThreadId::new();
+		let thread =
+			Box::new(Thread::new(id, self.name, self.priority, self.affinity).with_entry(entry));
+		let thread_ref = ThreadRef::new(&thread);
+
+		let mut threads = THREADS.lock();
+		reap(&mut threads);
+		threads.insert(id, thread);
+		for queue in &RUN_QUEUES {
+			queue.lock().reserve(threads.len());
+		}
+		drop(threads);
+
+		thread_ref.cpu.store(least_loaded(self.affinity), Ordering::Relaxed);
+		enqueue(thread_ref);
+		id
+	}
+}
+
+impl Default for Builder {
+	fn default() -> Self { Self::new() }
+}
+
+/// Turns the calling code into the boot thread, [`ThreadId::BOOT`], and gives the boot processor
+/// its idle thread. Needs the heap.
+///
+/// The boot thread stays on the boot processor: it runs the executor, whose wakeups come from
+/// interrupts only that core takes
+pub fn init() {
+	let cpu = percpu::current().id();
+	let boot = Thread::new(ThreadId::BOOT, "main", Priority::Normal, 1 << cpu).running_here();
+	let idle = Thread::new(ThreadId::new(), "idle", Priority::Idle, 1 << cpu).with_entry(idle);
+	register(cpu, Box::new(boot), Some(Box::new(idle)));
+}
+
+/// Turns the calling code into the idle thread of an application processor, whose idle loop
+/// must [`yield_now`] whenever [`has_ready`]
+pub fn init_cpu() {
+	let cpu = percpu::current().id();
+	let idle = Thread::new(ThreadId::new(), "idle", Priority::Idle, 1 << cpu).running_here();
+	register(cpu, Box::new(idle), None);
+}
+
+/// Makes `current` the thread running on `cpu` and `idle` its idle thread, `current` itself if
+/// `None`, and starts scheduling there
+fn register(cpu: usize, current: Box<Thread>, idle: Option<Box<Thread>>) {
+	let mut threads = THREADS.lock();
+	let mut queue = RUN_QUEUES[cpu].lock();
+	queue.current = Some(ThreadRef::new(&current));
+	queue.idle = Some(ThreadRef::new(idle.as_ref().unwrap_or(&current)));
+	percpu::current().current_task.store(current.id.0, Ordering::Relaxed);
+	assert!(threads.insert(current.id, current).is_none(), "Single entry point");
+	if let Some(idle) = idle {
+		threads.insert(idle.id, idle);
+	}
+	queue.reserve(threads.len());
+	drop(queue);
+	drop(threads);
+
+	ACTIVE.fetch_or(1 << cpu, Ordering::Release);
+}
+
+/// Starts a thread running `entry` with the defaults of [`Builder`]
+pub fn spawn(entry: fn()) -> ThreadId { Builder::new().spawn(entry) }
+
+/// Id of the calling thread
+pub fn current() -> ThreadId { ThreadId(percpu::current().current_task.load(Ordering::Relaxed)) }
+
+/// Whether the calling thread runs on a stack of its own, which it needs to enter ring 3
+pub fn has_own_stack() -> bool {
+	THREADS.lock().get(&current()).is_some_and(|thread| thread.stack.is_some())
+}
+
+/// Whether threads wait in the run queue of the calling core
+pub fn has_ready() -> bool { RUN_QUEUES[percpu::current().id()].lock().queued() > 0 }
+
+/// Gives the core to the next ready thread of at least the same priority, returning right away
+/// if there is none. Must not be called while holding a spinlock
+pub fn yield_now() {
+	assert!(percpu::current().held_locks.is_empty(), "yield_now while holding a lock");
+	schedule(State::Ready);
+}
+
+/// Ends the calling thread. It is freed by a later [`spawn`]
+pub fn exit() -> ! {
+	assert_ne!(current(), ThreadId::BOOT, "the boot thread cannot exit");
+	assert!(percpu::current().held_locks.is_empty(), "exit while holding a lock");
+	schedule(State::Dead);
+	unreachable!("an exited thread was scheduled again");
+}
+
+/// Blocks the calling thread until [`unpark`] is called for it, returning right away if that
+/// already happened since it last parked. It may also return spuriously, so callers check what
+/// they wait for in a loop
+pub fn park() {
+	let cpu = percpu::current();
+	assert!(!cpu.in_interrupt(), "park in an interrupt handler");
+	assert!(cpu.held_locks.is_empty(), "park while holding a lock");
+	schedule(State::Blocked);
+}
+
+/// Like [`park`], but the timer unparks the thread after `timeout_ms` at the latest
+pub fn park_timeout(timeout_ms: u64) {
+	let mut sleep = time::sleep(timeout_ms);
+	let waker = waker(current());
+	if Pin::new(&mut sleep).poll(&mut Context::from_waker(&waker)).is_pending() {
+		park();
+	}
+}
+
+/// Makes thread `id` ready again if it is parked, otherwise its next [`park`] returns right
+/// away. Safe to call from interrupt handlers
+pub fn unpark(id: ThreadId) {
+	// Held until the thread is queued, it cannot exit and be freed in between
+	let threads = THREADS.lock();
+	let Some(thread) = threads.get(&id) else { return };
+	let thread = ThreadRef::new(thread);
+
+	thread.unparked.store(true, Ordering::SeqCst);
+	let blocked = State::Blocked as u8;
+	let ready = State::Ready as u8;
+	if thread.state.compare_exchange(blocked, ready, Ordering::SeqCst, Ordering::Relaxed).is_ok() {
+		thread.unparked.store(false, Ordering::Relaxed);
+		enqueue(thread);
+	}
+}
+
+/// A [`Waker`] that unparks thread `id`, so threads can wait on futures like
+/// [`crate::time::Sleep`]. It does not allocate
+pub fn waker(id: ThreadId) -> Waker {
+	const VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+	fn clone(data: *const ()) -> RawWaker { RawWaker::new(data, &VTABLE) }
+	fn wake(data: *const ()) { unpark(ThreadId(data as usize)) }
+	fn drop(_data: *const ()) {}
+
+	unsafe { Waker::from_raw(RawWaker::new(id.0 as *const (), &VTABLE)) }
+}
+
+/// Blocks the calling thread for at least `ms` milliseconds, letting others run
+pub fn sleep(ms: u64) {
+	let end = time::uptime_ms().saturating_add(ms);
+	loop {
+		let now = time::uptime_ms();
+		if now >= end {
+			break;
+		}
+		park_timeout(end - now);
+	}
+}
+
+/// Restricts thread `id` to the cores whose bit is set in `cpus`. A queued thread moves right
+/// away, a running one when it is next switched out. Fails if there is no such thread, it is an
+/// idle thread or none of the cores is active
+pub fn set_affinity(id: ThreadId, cpus: u64) -> bool {
+	if cpus & ACTIVE.load(Ordering::Acquire) == 0 {
+		return false;
+	}
+
+	let threads = THREADS.lock();
+	let Some(thread) = threads.get(&id).filter(|thread| thread.priority != Priority::Idle) else {
+		return false;
+	};
+	let thread = ThreadRef::new(thread);
+	thread.affinity.store(cpus, Ordering::Relaxed);
+
+	let cpu = thread.cpu.load(Ordering::Relaxed);
+	let moved = !thread.allowed(cpu) && RUN_QUEUES[cpu].lock().remove(thread);
+	drop(threads);
+	if moved {
+		enqueue(thread);
+	}
+
+	if id == current() && !thread.allowed(percpu::current().id()) {
+		yield_now();
+	}
+	true
+}
+
+/// Takes `cpu` out of scheduling before it goes offline: no thread is queued there anymore, the
+/// ready ones move to the other cores and the running one follows once the reschedule IPI
+/// preempts it, leaving the core to its idle thread
+pub fn stop_cpu(cpu: usize) {
+	ACTIVE.fetch_and(!(1 << cpu), Ordering::AcqRel);
+	loop {
+		// The lock is dropped before queueing the thread elsewhere
+		let Some(thread) = RUN_QUEUES[cpu].lock().pop(Priority::Idle) else { break };
+		enqueue(thread);
+	}
+	kick(cpu);
+}
+
+/// Called by the timer interrupt handler on every tick. Only the boot processor takes the
+/// timer, so it asks the other cores to switch threads when a time slice ends, and periodically
+/// moves threads from busy cores to idle ones
+pub fn tick() {
+	let now = time::ticks();
+	if now % TIME_SLICE == 0 {
+		let me = percpu::current().id();
+		for cpu in active_cpus().filter(|&cpu| cpu != me) {
+			if RUN_QUEUES[cpu].lock().queued() > 0 {
+				kick(cpu);
+			}
+		}
+	}
+
+	if now % BALANCE_INTERVAL == 0 {
+		balance();
+	}
+}
+
+/// Called by the timer and reschedule interrupt handlers once they are done with the
+/// interrupt. Switches threads when a higher priority one is ready or the current one used up
+/// its time slice, unless it was interrupted holding a lock or inside another handler
+pub fn preempt() {
+	let cpu = percpu::current();
+	if cpu.in_interrupt() || !cpu.held_locks.is_empty() {
+		return;
+	}
+
+	let queue = RUN_QUEUES[cpu.id()].lock();
+	// A stopped core keeps nothing but its idle thread
+	let leave = !is_active(cpu.id()) && queue.current != queue.idle;
+	let preempt = leave || queue.should_preempt();
+	drop(queue);
+	if preempt {
+		schedule(State::Ready);
+	}
+}
+
+/// Prints every thread with its state and the CPU time it consumed, in the spirit of `ps`
+pub fn dump() {
+	println!("{:>5} {:>4} {:<7} {:<8} {:>10}  NAME", "TID", "CPU", "PRIO", "STATE", "TIME");
+	for thread in THREADS.lock().values() {
+		println!(
+			"{:>5} {:>4} {:<7} {:<8} {:>8}ms  {}",
+			thread.id.0,
+			thread.cpu.load(Ordering::Relaxed),
+			format!("{:?}", thread.priority),
+			format!("{:?}", thread.state()),
+			thread.cpu_time() * 1000 / time::TIMER_HZ,
+			thread.name
+		);
+	}
+}
+
+/// Switches the calling core to its next thread, leaving the current one in `state`: queued
+/// again if [`State::Ready`], until [`unpark`] if [`State::Blocked`], for good if
+/// [`State::Dead`]. Returns once the current thread runs again, right away if nothing else
+/// should run
+fn schedule(state: State) {
+	let cpu = percpu::current();
+	let me = cpu.id();
+	let enabled = interrupts::are_enabled();
+	interrupts::disable();
+	watchdog::touch();
+
+	let mut queue = RUN_QUEUES[me].lock();
+	let next = match (queue.current, queue.idle) {
+		// Woken before it could block, it keeps running
+		(Some(prev), Some(_)) if state == State::Blocked && !prev.block() => None,
+		(Some(prev), Some(idle)) if prev == idle => queue.pop(Priority::Idle),
+		// A thread that must leave takes whatever is ready, the idle thread if nothing is
+		(Some(prev), Some(idle))
+			if state != State::Ready || !prev.allowed(me) || !is_active(me) =>
+		{
+			Some(queue.pop(Priority::Idle).unwrap_or(idle))
+		}
+		(Some(prev), Some(_)) => queue.pop(prev.priority),
+		_ => None,
+	};
+	let (Some(prev), Some(idle), Some(next)) = (queue.current, queue.idle, next) else {
+		drop(queue);
+		if enabled {
+			interrupts::enable();
+		}
+		return;
+	};
+
+	let now = time::ticks();
+	let ran = now.saturating_sub(prev.switched_in.load(Ordering::Relaxed));
+	prev.cpu_time.fetch_add(ran, Ordering::Relaxed);
+	next.switched_in.store(now, Ordering::Relaxed);
+	next.cpu.store(me, Ordering::Relaxed);
+	next.set_state(State::Running);
+
+	let requeue = state == State::Ready && prev != idle;
+	let migrate = requeue && (!prev.allowed(me) || !is_active(me));
+	if requeue && !migrate {
+		queue.push(prev);
+	} else if state != State::Blocked {
+		// A blocked thread got its state from `block`, and may be queued again already
+		prev.set_state(state);
+	}
+	queue.current = Some(next);
+	cpu.current_task.store(next.id.0, Ordering::Relaxed);
+	drop(queue);
+
+	// Another core may pick it up right away, it waits for `on_cpu` to clear
+	if migrate {
+		enqueue(prev);
+	}
+
+	unsafe {
+		// The core that last ran `next` may still be saving it
+		while next.on_cpu.swap(true, Ordering::Acquire) {
+			core::hint::spin_loop();
+		}
+		// Interrupts taken in ring 3 land on the kernel stack of the thread
+		if let Some(top) = next.stack_top() {
+			gdt::set_kernel_stack(top);
+		}
+		(*prev.fpu.get()).save();
+		(*next.fpu.get()).restore();
+		thread_switch(prev.rsp.get(), *next.rsp.get(), &prev.on_cpu);
+	}
+
+	if enabled {
+		interrupts::enable();
+	}
+}
+
+/// Queues a ready thread on the core it last ran on, or on the least loaded one it may run on
+/// if it must move, and asks that core to reschedule
+fn enqueue(thread: ThreadRef) {
+	let mut cpu = thread.cpu.load(Ordering::Relaxed);
+	if !thread.allowed(cpu) || !is_active(cpu) {
+		cpu = least_loaded(thread.affinity.load(Ordering::Relaxed));
+		thread.cpu.store(cpu, Ordering::Relaxed);
+	}
+
+	RUN_QUEUES[cpu].lock().push(thread);
+	kick(cpu);
+}
+
+/// Asks another core to reschedule. The calling core notices its own ready threads on the next
+/// tick, or when its idle thread wakes up
+fn kick(cpu: usize) {
+	if cpu != percpu::current().id() {
+		ipi::send(ipi::Target::Cpu(cpu), InterruptIndex::Reschedule as u8);
+	}
+}
+
+fn is_active(cpu: usize) -> bool { ACTIVE.load(Ordering::Acquire) & (1 << cpu) != 0 }
+
+fn active_cpus() -> impl Iterator<Item = usize> {
+	let active = ACTIVE.load(Ordering::Acquire);
+	(0..MAX_CPUS).filter(move |&cpu| active & (1 << cpu) != 0)
+}
+
+fn load(cpu: &usize) -> usize { RUN_QUEUES[*cpu].lock().load() }
+
+/// The active core in `cpus` with the fewest threads wanting to run. A thread whose cores all
+/// went away runs anywhere
+fn least_loaded(cpus: u64) -> usize {
+	active_cpus()
+		.filter(|&cpu| cpus & (1 << cpu) != 0)
+		.min_by_key(load)
+		.or_else(|| active_cpus().min_by_key(load))
+		.expect("no active core")
+}
+
+/// Moves one thread from the busiest core to the least busy one if their loads differ by two
+/// or more
+fn balance() {
+	let (Some(busiest), Some(idlest)) =
+		(active_cpus().max_by_key(load), active_cpus().min_by_key(load))
+	else {
+		return;
+	};
+	if load(&busiest) < load(&idlest) + 2 {
+		return;
+	}
+
+	// Two run queues are always taken lowest numbered first
+	let (mut from, mut to) = if busiest < idlest {
+		let from = RUN_QUEUES[busiest].lock();
+		(from, RUN_QUEUES[idlest].lock())
+	} else {
+		let to = RUN_QUEUES[idlest].lock();
+		(RUN_QUEUES[busiest].lock(), to)
+	};
+	let Some(thread) = from.steal(idlest) else { return };
+	thread.cpu.store(idlest, Ordering::Relaxed);
+	to.push(thread);
+	drop(to);
+	drop(from);
+	kick(idlest);
+}
+
+/// Frees the threads that exited, once no core is left on their stack
+fn reap(threads: &mut BTreeMap<ThreadId, Box<Thread>>) {
+	threads
+		.retain(|_, thread| thread.state() != State::Dead || thread.on_cpu.load(Ordering::Acquire));
+}
+
+/// Body of the idle thread of the boot processor. Halts until an interrupt makes a thread ready
+fn idle() {
+	loop {
+		// Checking with interrupts off closes the window where the wakeup arrives right before
+		// the `hlt`
+		interrupts::disable();
+		watchdog::touch();
+		if has_ready() {
+			interrupts::enable();
+			yield_now();
+		} else {
+			interrupts::enable_and_hlt();
+		}
+	}
+}
+
+/// Where a new thread lands after its first switch, instead of returning into [`schedule`]
+extern "C" fn thread_entry(entry: usize) -> ! {
+	interrupts::enable();
+	let entry = unsafe { core::mem::transmute::<usize, fn()>(entry) };
+	entry();
+	exit()
+}
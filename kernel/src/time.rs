@@ -0,0 +1,137 @@
+use core::{
+	future::Future,
+	pin::Pin,
+	sync::atomic::{AtomicU64, Ordering},
+	task::{Context, Poll, Waker},
+};
+
+use x86_64::instructions::port::Port;
+
+use crate::mutex::IrqMutex;
+
+/// Frequency the PIT is programmed to, every tick is one millisecond
+pub const TIMER_HZ: u64 = 1000;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+/// Pending [`Sleep`] futures the timer can wake, later ones fall back to polling
+const MAX_SLEEPERS: usize = 64;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static SLEEPERS: IrqMutex<[Option<Sleeper>; MAX_SLEEPERS]> =
+	IrqMutex::new([const { None }; MAX_SLEEPERS]);
+/// Earliest deadline in [`SLEEPERS`], so most ticks do not take the lock
+static NEXT_WAKEUP: AtomicU64 = AtomicU64::new(u64::MAX);
+
+#[derive(Debug)]
+struct Sleeper {
+	/// Tick to wake at, `u64::MAX` once woken
+	deadline: u64,
+	waker: Waker,
+}
+
+/// Programs channel 0 of the PIT as a rate generator firing `TIMER_HZ` times per second
+pub fn init_pit() {
+	let divisor = (PIT_FREQUENCY / TIMER_HZ) as u16;
+	let mut command = Port::<u8>::new(PIT_COMMAND);
+	let mut channel = Port::<u8>::new(PIT_CHANNEL_0);
+	unsafe {
+		// Channel 0, lobyte/hibyte access, mode 2
+		command.write(0x34);
+		channel.write(divisor as u8);
+		channel.write((divisor >> 8) as u8);
+	}
+}
+
+/// Called by the timer interrupt handler
+pub fn tick() {
+	let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+	if now >= NEXT_WAKEUP.load(Ordering::Relaxed) {
+		wake_sleepers(now);
+	}
+}
+
+/// Wakes the sleepers whose deadline passed. The wakers stay in their slots, dropping one is
+/// left to its [`Sleep`] so the interrupt never frees memory
+fn wake_sleepers(now: u64) {
+	let mut sleepers = SLEEPERS.lock();
+	let mut next = u64::MAX;
+	for sleeper in sleepers.iter_mut().flatten() {
+		if sleeper.deadline <= now {
+			sleeper.waker.wake_by_ref();
+			sleeper.deadline = u64::MAX;
+		}
+		next = next.min(sleeper.deadline);
+	}
+	NEXT_WAKEUP.store(next, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 { TICKS.load(Ordering::Relaxed) }
+
+pub fn uptime_ms() -> u64 { ticks() * 1000 / TIMER_HZ }
+
+/// Halts until at least `ms` milliseconds passed. Interrupts must be enabled, otherwise the
+/// timer never advances
+pub fn sleep_ms(ms: u64) {
+	let end = deadline(ms);
+	while ticks() < end {
+		crate::watchdog::touch();
+		x86_64::instructions::hlt();
+	}
+}
+
+/// Completes once `ms` milliseconds passed, without keeping the core busy
+pub fn sleep(ms: u64) -> Sleep { Sleep { deadline: deadline(ms), slot: None } }
+
+/// Tick at least `ms` milliseconds from now, at least one tick away. Saturates instead of
+/// overflowing, the duration may come from ring 3
+fn deadline(ms: u64) -> u64 { ticks().saturating_add((ms.saturating_mul(TIMER_HZ) / 1000).max(1)) }
+
+/// Future returned by [`sleep`], woken by the timer interrupt
+#[derive(Debug)]
+pub struct Sleep {
+	deadline: u64,
+	/// Index in [`SLEEPERS`] once registered
+	slot: Option<usize>,
+}
+
+impl Sleep {
+	fn release(&mut self) {
+		if let Some(slot) = self.slot.take() {
+			let sleeper = SLEEPERS.lock()[slot].take();
+			drop(sleeper);
+		}
+	}
+}
+
+impl Future for Sleep {
+	type Output = ();
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+		if ticks() >= self.deadline {
+			self.release();
+			return Poll::Ready(());
+		}
+
+		let mut sleepers = SLEEPERS.lock();
+		let Some(slot) = self.slot.or_else(|| sleepers.iter().position(Option::is_none)) else {
+			// Every slot is taken, ask to be polled again
+			drop(sleepers);
+			cx.waker().wake_by_ref();
+			return Poll::Pending;
+		};
+		self.slot = Some(slot);
+		let old =
+			sleepers[slot].replace(Sleeper { deadline: self.deadline, waker: cx.waker().clone() });
+		NEXT_WAKEUP.fetch_min(self.deadline, Ordering::Relaxed);
+		drop(sleepers);
+		drop(old);
+		Poll::Pending
+	}
+}
+
+impl Drop for Sleep {
+	fn drop(&mut self) { self.release(); }
+}
@@ -0,0 +1,58 @@
+// Ring 3 code runs in the address space of the kernel, limited to the pages mapped with
+// `mem::map_user_page`. It gets back into the kernel through system calls, interrupts and
+// exceptions, on the kernel stack of its thread that `thread::schedule` keeps in the TSS, and a
+// fault ends the thread instead of the kernel
+
+use core::arch::asm;
+
+use x86_64::{instructions::interrupts, registers::rflags::RFlags, VirtAddr};
+
+use crate::{gdt, thread};
+
+/// Drops the calling thread to ring 3, at `entry` with `stack` as its stack pointer. What was
+/// on its kernel stack is gone, interrupts from ring 3 start over at the top of it. The thread
+/// must have been started by [`thread::spawn`], the stacks cores boot on are not tracked
+pub fn enter(entry: VirtAddr, stack: VirtAddr) -> ! {
+	assert!(thread::has_own_stack(), "entering ring 3 on a boot stack");
+	let selectors = gdt::selectors();
+	let code = u64::from(selectors.user_code().0);
+	let data = u64::from(selectors.user_data().0);
+	// Bit 1 of RFLAGS is reserved and always set
+	let rflags = RFlags::INTERRUPT_FLAG.bits() | 0x2;
+
+	// Kept off until `iretq`, an interrupt with the user GS base loaded would find no per-CPU area
+	interrupts::disable();
+	unsafe {
+		asm!(
+			"push {data}",
+			"push {stack}",
+			"push {rflags}",
+			"push {code}",
+			"push {entry}",
+			// No kernel value leaks into the registers ring 3 starts with
+			"xor eax, eax",
+			"xor ebx, ebx",
+			"xor ecx, ecx",
+			"xor edx, edx",
+			"xor esi, esi",
+			"xor edi, edi",
+			"xor ebp, ebp",
+			"xor r8d, r8d",
+			"xor r9d, r9d",
+			"xor r10d, r10d",
+			"xor r11d, r11d",
+			"xor r12d, r12d",
+			"xor r13d, r13d",
+			"xor r14d, r14d",
+			"xor r15d, r15d",
+			"swapgs",
+			"iretq",
+			data = in(reg) data,
+			stack = in(reg) stack.as_u64(),
+			rflags = in(reg) rflags,
+			code = in(reg) code,
+			entry = in(reg) entry.as_u64(),
+			options(noreturn),
+		)
+	}
+}
@@ -0,0 +1,77 @@
+use alloc::collections::VecDeque;
+
+use crate::{
+	mutex::IrqMutex,
+	thread::{self, ThreadId},
+	time,
+};
+
+/// Threads sleeping until an event happens, woken by whoever signals it, interrupt handlers
+/// included. Waiting must happen in a thread
+pub struct WaitQueue {
+	waiters: IrqMutex<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+	pub const fn new() -> WaitQueue { WaitQueue { waiters: IrqMutex::new(VecDeque::new()) } }
+
+	/// Parks the calling thread until `condition` holds. It is checked with the queue locked and
+	/// interrupts disabled, so it must be quick, and a notify that follows a change to what it
+	/// reads cannot slip in between the check and the parking
+	pub fn wait_until(&self, condition: impl FnMut() -> bool) { self.wait(condition, None); }
+
+	/// Like [`WaitQueue::wait_until`], but gives up after `timeout_ms`. Returns whether
+	/// `condition` holds
+	pub fn wait_until_timeout(&self, condition: impl FnMut() -> bool, timeout_ms: u64) -> bool {
+		self.wait(condition, Some(time::uptime_ms().saturating_add(timeout_ms)))
+	}
+
+	fn wait(&self, mut condition: impl FnMut() -> bool, deadline: Option<u64>) -> bool {
+		let me = thread::current();
+		loop {
+			let mut waiters = self.waiters.lock();
+			if condition() {
+				return true;
+			}
+			let now = time::uptime_ms();
+			if deadline.is_some_and(|deadline| now >= deadline) {
+				return false;
+			}
+			waiters.push_back(me);
+			drop(waiters);
+
+			// A notify between queueing and parking makes the park return right away
+			match deadline {
+				Some(deadline) => thread::park_timeout(deadline - now),
+				None => thread::park(),
+			}
+			// Still queued after a timeout or a spurious wakeup
+			self.waiters.lock().retain(|&waiter| waiter != me);
+		}
+	}
+
+	/// Wakes the thread waiting the longest. Returns whether there was one
+	pub fn notify_one(&self) -> bool {
+		let waiter = self.waiters.lock().pop_front();
+		waiter.map(thread::unpark).is_some()
+	}
+
+	/// Wakes every waiting thread and returns how many there were
+	pub fn notify_all(&self) -> usize {
+		// Taken at once, threads that queue again after waking wait for the next notify
+		let waiters = core::mem::take(&mut *self.waiters.lock());
+		let woken = waiters.len();
+		waiters.into_iter().for_each(thread::unpark);
+		woken
+	}
+}
+
+impl Default for WaitQueue {
+	fn default() -> Self { Self::new() }
+}
+
+impl core::fmt::Debug for WaitQueue {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.debug_struct("WaitQueue").field("waiters", &self.waiters.lock().len()).finish()
+	}
+}
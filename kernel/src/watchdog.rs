@@ -0,0 +1,297 @@
+use core::{
+	panic::Location,
+	sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+	apic, backtrace, frame,
+	interrupts::InterruptIndex,
+	ipi,
+	percpu::{self, PerCpu},
+	println, smp, time,
+};
+
+/// Milliseconds between two checks of the other cores, also how often they are asked for a
+/// heartbeat
+pub const CHECK_INTERVAL_MS: u64 = 1000;
+/// Locks a core can be holding before the extra ones stop being tracked
+const MAX_HELD_LOCKS: usize = 8;
+/// Iterations to wait for a stuck core to dump its stack from the NMI handler
+const DUMP_TIMEOUT: usize = 100_000_000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Timer ticks seen by the last [`check_boot_cpu`], and for how long they have not moved
+static BOOT_CPU_TICKS: AtomicU64 = AtomicU64::new(0);
+static BOOT_CPU_STALLED_MS: AtomicU64 = AtomicU64::new(0);
+/// How long a core may go without scheduling before it is reported
+static SOFT_THRESHOLD_MS: AtomicU64 = AtomicU64::new(20_000);
+/// How long a core may go without taking an interrupt before it is reported
+static HARD_THRESHOLD_MS: AtomicU64 = AtomicU64::new(10_000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lockup {
+	/// The core has not gone idle or scheduled, but still takes interrupts
+	Soft,
+	/// The core has not taken an interrupt, so it runs with interrupts disabled
+	Hard,
+}
+
+/// Watchdog state of a core, kept in its per-CPU area
+#[derive(Debug)]
+pub struct Watchdog {
+	/// Tick at which the core last went idle or scheduled
+	touched: AtomicU64,
+	/// Tick at which the core last took an interrupt
+	heartbeat: AtomicU64,
+	/// Set by the detecting core before sending the NMI that dumps this core's stack
+	dump_requested: AtomicBool,
+	dumped: AtomicBool,
+}
+
+impl Watchdog {
+	pub const fn new() -> Watchdog {
+		Watchdog {
+			touched: AtomicU64::new(0),
+			heartbeat: AtomicU64::new(0),
+			dump_requested: AtomicBool::new(false),
+			dumped: AtomicBool::new(false),
+		}
+	}
+}
+
+impl Default for Watchdog {
+	fn default() -> Self { Self::new() }
+}
+
+/// Locks a core currently holds and where they were taken, shown in lockup reports
+#[derive(Debug)]
+pub struct HeldLocks {
+	/// Address of the lock, zero for a free slot
+	locks: [AtomicUsize; MAX_HELD_LOCKS],
+	/// `&'static Location` of the `lock` call
+	sites: [AtomicUsize; MAX_HELD_LOCKS],
+	/// Whether the lock is only held for reading
+	shared: [AtomicBool; MAX_HELD_LOCKS],
+}
+
+impl HeldLocks {
+	pub const fn new() -> HeldLocks {
+		HeldLocks {
+			locks: [const { AtomicUsize::new(0) }; MAX_HELD_LOCKS],
+			sites: [const { AtomicUsize::new(0) }; MAX_HELD_LOCKS],
+			shared: [const { AtomicBool::new(false) }; MAX_HELD_LOCKS],
+		}
+	}
+
+	pub(crate) fn iter(
+		&self,
+	) -> impl Iterator<Item = (usize, Option<&'static Location<'static>>)> + '_ {
+		self.locks.iter().zip(&self.sites).filter_map(|(lock, site)| {
+			let lock = lock.load(Ordering::Relaxed);
+			let site = site.load(Ordering::Relaxed) as *const Location<'static>;
+			(lock != 0).then(|| (lock, unsafe { site.as_ref() }))
+		})
+	}
+
+	pub(crate) fn contains(&self, lock: usize) -> bool { self.iter().any(|(held, _)| held == lock) }
+
+	/// Whether `lock` is held for reading, see [`read_lock_acquired`]
+	#[cfg(feature = "lockdep")]
+	pub(crate) fn contains_shared(&self, lock: usize) -> bool {
+		self.locks.iter().zip(&self.shared).any(|(held, shared)| {
+			held.load(Ordering::Relaxed) == lock && shared.load(Ordering::Relaxed)
+		})
+	}
+
+	pub(crate) fn is_empty(&self) -> bool { self.iter().next().is_none() }
+}
+
+impl Default for HeldLocks {
+	fn default() -> Self { Self::new() }
+}
+
+/// Entry of a lock in the [`HeldLocks`] of the core that took it, cleared on drop. It remembers
+/// the core, the guard may be dropped elsewhere
+#[derive(Debug)]
+pub struct HeldLock {
+	cpu: &'static PerCpu,
+	slot: usize,
+}
+
+impl Drop for HeldLock {
+	fn drop(&mut self) {
+		let held = &self.cpu.held_locks;
+		held.sites[self.slot].store(0, Ordering::Relaxed);
+		held.shared[self.slot].store(false, Ordering::Relaxed);
+		held.locks[self.slot].store(0, Ordering::Release);
+	}
+}
+
+/// Records that the calling core took `lock` at `site`. Returns `None` when every slot is in use
+pub fn lock_acquired(lock: usize, site: &'static Location<'static>) -> Option<HeldLock> {
+	let cpu = percpu::current();
+	let held = &cpu.held_locks;
+	let slot = held.locks.iter().position(|slot| {
+		slot.compare_exchange(0, lock, Ordering::Acquire, Ordering::Relaxed).is_ok()
+	})?;
+	held.sites[slot].store(site as *const Location as usize, Ordering::Relaxed);
+	Some(HeldLock { cpu, slot })
+}
+
+/// Like [`lock_acquired`], for the read side of a reader-writer lock
+pub fn read_lock_acquired(lock: usize, site: &'static Location<'static>) -> Option<HeldLock> {
+	let held = lock_acquired(lock, site)?;
+	held.cpu.held_locks.shared[held.slot].store(true, Ordering::Relaxed);
+	Some(held)
+}
+
+/// Changes how long a core may go without scheduling (`soft_ms`) or without taking an interrupt
+/// (`hard_ms`) before the machine is brought down
+pub fn set_thresholds(soft_ms: u64, hard_ms: u64) {
+	SOFT_THRESHOLD_MS.store(soft_ms, Ordering::Relaxed);
+	HARD_THRESHOLD_MS.store(hard_ms, Ordering::Relaxed);
+}
+
+/// Starts checking every online core, once they are all done booting. The first AP watches the
+/// boot processor from its own APIC timer, see [`check_boot_cpu`]
+pub fn enable() {
+	let now = time::ticks();
+	for cpu in (0..smp::MAX_CPUS).filter(|&cpu| smp::is_online(cpu)).map(percpu::get) {
+		cpu.watchdog.touched.store(now, Ordering::Relaxed);
+		cpu.watchdog.heartbeat.store(now, Ordering::Relaxed);
+	}
+	BOOT_CPU_TICKS.store(now, Ordering::Relaxed);
+	ENABLED.store(true, Ordering::Release);
+
+	if let Some(watcher) = (1..smp::MAX_CPUS).find(|&cpu| smp::is_online(cpu)) {
+		let _ = smp::run_on(watcher, watch_boot_cpu);
+	}
+}
+
+/// Runs on the AP watching the boot processor, arms its APIC timer to call [`check_boot_cpu`]
+fn watch_boot_cpu() {
+	let Some(lapic) = apic::LAPIC.get() else { return };
+	let per_ms = u64::from(lapic.calibrate_timer());
+	let counts = (per_ms * CHECK_INTERVAL_MS).min(u32::MAX as u64) as u32;
+	lapic.start_periodic_timer(InterruptIndex::WatchdogTimer as u8, counts);
+}
+
+/// Tells the watchdog the calling core is making progress. Idle loops and the scheduler call it
+pub fn touch() { percpu::current().watchdog.touched.store(time::ticks(), Ordering::Relaxed); }
+
+/// Tells the watchdog the calling core takes interrupts. Called by the timer and wakeup handlers
+pub fn heartbeat() { percpu::current().watchdog.heartbeat.store(time::ticks(), Ordering::Relaxed); }
+
+/// Looks for stuck cores, from the timer interrupt every [`CHECK_INTERVAL_MS`].
+///
+/// Only the boot processor gets the timer, so the other cores are woken with an IPI to prove
+/// they still take interrupts. A boot processor that stops taking them stops running this too,
+/// [`check_boot_cpu`] catches it from another core
+pub fn check() {
+	if !ENABLED.load(Ordering::Acquire) {
+		return;
+	}
+
+	let now = time::ticks();
+	let elapsed_ms = |since: &AtomicU64| {
+		now.saturating_sub(since.load(Ordering::Relaxed)) * 1000 / time::TIMER_HZ
+	};
+
+	let me = percpu::current().id();
+	for cpu in (0..smp::MAX_CPUS).filter(|&cpu| smp::is_online(cpu)) {
+		let watchdog = &percpu::get(cpu).watchdog;
+
+		let stuck = elapsed_ms(&watchdog.heartbeat);
+		if cpu != me && stuck > HARD_THRESHOLD_MS.load(Ordering::Relaxed) {
+			report(cpu, Lockup::Hard, stuck);
+		}
+
+		let stuck = elapsed_ms(&watchdog.touched);
+		if stuck > SOFT_THRESHOLD_MS.load(Ordering::Relaxed) {
+			report(cpu, Lockup::Soft, stuck);
+		}
+	}
+
+	ipi::send(ipi::Target::AllButSelf, InterruptIndex::Wakeup as u8);
+}
+
+/// Looks for a hard lockup of the boot processor, from the APIC timer of the AP watching it
+/// every [`CHECK_INTERVAL_MS`]. The timer ticks only move while the boot processor takes
+/// interrupts, so this counts time in its own calls. With a single core nothing watches it
+pub fn check_boot_cpu() {
+	if !ENABLED.load(Ordering::Acquire) {
+		return;
+	}
+
+	let now = time::ticks();
+	if BOOT_CPU_TICKS.swap(now, Ordering::Relaxed) != now {
+		BOOT_CPU_STALLED_MS.store(0, Ordering::Relaxed);
+		return;
+	}
+	let stalled =
+		BOOT_CPU_STALLED_MS.fetch_add(CHECK_INTERVAL_MS, Ordering::Relaxed) + CHECK_INTERVAL_MS;
+	if stalled > HARD_THRESHOLD_MS.load(Ordering::Relaxed) {
+		report(0, Lockup::Hard, stalled);
+	}
+}
+
+/// Body of the NMI handler. Dumps the stack if a lockup report asked for it.
+///
+/// The NMI may have stopped this core in the middle of printing, holding the console locks.
+/// Printing notices through [`percpu::start_printing`] and writes to the serial port without
+/// the lock then, where [`report`] only frees locks held by the stuck core on the reporter's
+/// behalf
+pub fn handle_nmi() {
+	let cpu = percpu::current();
+	if !cpu.watchdog.dump_requested.swap(false, Ordering::Acquire) {
+		println!("NMI on CPU {}", cpu.id());
+		return;
+	}
+
+	println!("watchdog: CPU {} was running", cpu.id());
+	backtrace::print();
+	cpu.watchdog.dumped.store(true, Ordering::Release);
+}
+
+fn report(cpu: usize, kind: Lockup, stuck_ms: u64) -> ! {
+	ENABLED.store(false, Ordering::Relaxed);
+	let stuck = percpu::get(cpu);
+
+	// The stuck core may hold the console, and it is not giving it back
+	if let Some(writer) = frame::WRITER.get() {
+		if stuck.held_locks.contains(writer as *const _ as usize) {
+			unsafe { writer.force_unlock() };
+		}
+	}
+	#[cfg(feature = "serial")]
+	if let Some(serial) = crate::once_lock::Lazy::get(&crate::serial::SERIAL1) {
+		if stuck.held_locks.contains(serial as *const _ as usize) {
+			unsafe { serial.force_unlock() };
+		}
+	}
+
+	println!("watchdog: {:?} lockup on CPU {}, stuck for {} ms", kind, cpu, stuck_ms);
+	println!("Held locks:");
+	for (lock, site) in stuck.held_locks.iter() {
+		match site {
+			Some(site) => println!("  {:#018x} taken at {}", lock, site),
+			None => println!("  {:#018x}", lock),
+		}
+	}
+
+	if cpu == percpu::current().id() {
+		backtrace::print();
+	} else {
+		stuck.watchdog.dumped.store(false, Ordering::Relaxed);
+		stuck.watchdog.dump_requested.store(true, Ordering::Release);
+		ipi::send_nmi(cpu);
+		for _ in 0..DUMP_TIMEOUT {
+			if stuck.watchdog.dumped.load(Ordering::Acquire) {
+				break;
+			}
+			core::hint::spin_loop();
+		}
+	}
+
+	panic!("watchdog: {:?} lockup on CPU {}", kind, cpu);
+}
@@ -20,8 +20,12 @@ fn main() {
             .arg(format!("format=raw,file={bios_path}"));
     }
 
+    cmd.args(["-smp", "4"]);
+
     #[cfg(feature = "serial")]
     cmd.args(["-serial", "stdio"]);
+    #[cfg(feature = "qemu-exit")]
+    cmd.args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]);
     // cmd.args(["-nographic"]);
 
     println!("{:?}", cmd);
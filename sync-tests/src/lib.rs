@@ -0,0 +1,80 @@
+// The kernel's lock types and queues built for the host, with stand-ins for the kernel modules
+// they use. The tests drive them from real threads in `tests/stress.rs` and under the model
+// checker in `tests/model.rs`
+
+extern crate alloc;
+
+#[path = "../../kernel/src/atomic_waker.rs"]
+pub mod atomic_waker;
+#[path = "../../kernel/src/blocking.rs"]
+pub mod blocking;
+pub mod model;
+#[path = "../../kernel/src/mutex.rs"]
+pub mod mutex;
+#[path = "../../kernel/src/once_lock.rs"]
+pub mod once_lock;
+#[path = "../../kernel/src/ring.rs"]
+pub mod ring;
+pub mod sync;
+#[path = "../../kernel/src/wait_queue.rs"]
+pub mod wait_queue;
+
+/// Stand-in for `kernel::time`, lock timeouts count milliseconds since first use
+pub mod time {
+	use std::{sync::OnceLock, time::Instant};
+
+	static START: OnceLock<Instant> = OnceLock::new();
+
+	pub fn uptime_ms() -> u64 { START.get_or_init(Instant::now).elapsed().as_millis() as u64 }
+}
+
+/// Stand-in for `kernel::thread`, parking maps to the host threads' own
+pub mod thread {
+	use std::{collections::HashMap, sync::Mutex, thread::Thread, time::Duration};
+
+	pub use std::thread::ThreadId;
+
+	/// Every thread that asked for its id, so it can be unparked by id
+	static THREADS: Mutex<Option<HashMap<ThreadId, Thread>>> = Mutex::new(None);
+
+	pub fn current() -> ThreadId {
+		let thread = std::thread::current();
+		let id = thread.id();
+		THREADS.lock().unwrap().get_or_insert_with(HashMap::new).insert(id, thread);
+		id
+	}
+
+	pub fn park() { std::thread::park() }
+
+	pub fn park_timeout(timeout_ms: u64) {
+		std::thread::park_timeout(Duration::from_millis(timeout_ms))
+	}
+
+	pub fn unpark(id: ThreadId) {
+		if let Some(thread) = THREADS.lock().unwrap().as_ref().and_then(|threads| threads.get(&id))
+		{
+			thread.unpark();
+		}
+	}
+}
+
+/// Stand-in for `kernel::watchdog`, there are no cores whose held locks could be reported
+pub mod watchdog {
+	use core::panic::Location;
+
+	#[derive(Debug)]
+	pub struct HeldLock;
+
+	pub fn lock_acquired(_lock: usize, _site: &'static Location<'static>) -> Option<HeldLock> {
+		None
+	}
+
+	pub fn read_lock_acquired(_lock: usize, _site: &'static Location<'static>) -> Option<HeldLock> {
+		None
+	}
+}
+
+#[macro_export]
+macro_rules! println {
+	($($arg:tt)*) => (std::println!($($arg)*));
+}
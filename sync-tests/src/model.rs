@@ -0,0 +1,546 @@
+use std::{
+	cell::{RefCell, UnsafeCell},
+	collections::HashMap,
+	mem,
+	panic::{self, AssertUnwindSafe},
+	sync::{atomic::Ordering, Arc, Condvar, Mutex, MutexGuard},
+	thread,
+};
+
+/// Threads a model may run, the closure given to [`check`] included
+pub const MAX_THREADS: usize = 4;
+/// Preemptions explored per execution by [`check`]. Bounding them keeps the search tractable,
+/// and nearly every real bug needs only two or three
+pub const DEFAULT_PREEMPTION_BOUND: usize = 3;
+/// Scheduling points after which an execution is reported as livelocked
+const MAX_STEPS: usize = 10_000;
+
+thread_local! {
+	static CURRENT: RefCell<Option<(Arc<Execution>, usize)>> = const { RefCell::new(None) };
+}
+
+/// Unwinds the model threads once an execution failed. Raised with `resume_unwind`, so the panic
+/// hook stays quiet
+struct Abort;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct VClock([u64; MAX_THREADS]);
+
+impl VClock {
+	fn join(&mut self, other: &VClock) {
+		for (mine, theirs) in self.0.iter_mut().zip(other.0) {
+			*mine = (*mine).max(theirs);
+		}
+	}
+
+	fn tick(&mut self, thread: usize) -> u64 {
+		self.0[thread] += 1;
+		self.0[thread]
+	}
+
+	/// Whether event `at` of `thread` happened before this clock
+	fn has_seen(&self, thread: usize, at: u64) -> bool { self.0[thread] >= at }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+	Runnable,
+	/// Called `spin_loop`. It stays parked until an atomic is written after `epoch`, the epoch at
+	/// which it started the iteration of its loop that ended in the call
+	Spinning {
+		epoch: u64,
+	},
+	Joining(usize),
+	Finished,
+}
+
+#[derive(Debug)]
+struct Thread {
+	status: Status,
+	/// Epoch when the thread last came back from `spin_loop`
+	resumed: u64,
+	clock: VClock,
+	/// Clocks published to the relaxed loads of this thread, acquired by its next acquire fence
+	acquire_fence: VClock,
+	/// Clock at the last release fence of this thread, published by its relaxed stores
+	release_fence: VClock,
+}
+
+impl Thread {
+	fn new(clock: VClock) -> Thread {
+		Thread {
+			status: Status::Runnable,
+			resumed: 0,
+			clock,
+			acquire_fence: VClock::default(),
+			release_fence: VClock::default(),
+		}
+	}
+}
+
+/// A scheduling point with more than one runnable thread
+#[derive(Debug, Clone)]
+struct Choice {
+	options: Vec<usize>,
+	taken: usize,
+}
+
+/// What an atomic operation did. Decided after running it, a failed compare-exchange is a load
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Access {
+	Load(Ordering),
+	Store(Ordering),
+	Rmw(Ordering),
+}
+
+/// Last write and the reads since of a [`Tracked`] value, as `(thread, event)` pairs
+#[derive(Debug, Default)]
+struct Cell {
+	write: Option<(usize, u64)>,
+	reads: VClock,
+}
+
+#[derive(Debug)]
+struct State {
+	threads: Vec<Thread>,
+	/// The one thread allowed to run
+	active: usize,
+	/// Choices made by this execution, starting with the ones replayed from the previous one
+	path: Vec<Choice>,
+	depth: usize,
+	preemptions: usize,
+	preemption_bound: usize,
+	steps: usize,
+	/// Bumped by every atomic write, spinning threads only run again once it moved
+	epoch: u64,
+	/// Clock an acquire load of each atomic synchronizes with, keyed by address
+	atomics: HashMap<usize, VClock>,
+	cells: HashMap<usize, Cell>,
+	failure: Option<String>,
+}
+
+impl State {
+	fn enabled(&self, thread: usize) -> bool {
+		match self.threads[thread].status {
+			Status::Runnable => true,
+			Status::Spinning { epoch } => epoch < self.epoch,
+			Status::Joining(other) => self.threads[other].status == Status::Finished,
+			Status::Finished => false,
+		}
+	}
+
+	/// Picks the thread to run after `current`, following the replayed path as long as it lasts.
+	/// `current` comes first among the options, so the first execution preempts nobody
+	fn schedule(&mut self, current: usize) -> Option<usize> {
+		let stays = self.enabled(current);
+		let options: Vec<usize> = if stays && self.preemptions >= self.preemption_bound {
+			vec![current]
+		} else {
+			let others = (0..self.threads.len()).filter(|&thread| thread != current);
+			stays
+				.then_some(current)
+				.into_iter()
+				.chain(others.filter(|&t| self.enabled(t)))
+				.collect()
+		};
+
+		let next = match options.len() {
+			0 => return None,
+			1 => options[0],
+			_ => {
+				if self.depth == self.path.len() {
+					self.path.push(Choice { options: options.clone(), taken: 0 });
+				}
+				let choice = &self.path[self.depth];
+				assert_eq!(choice.options, options, "the model does not replay deterministically");
+				self.depth += 1;
+				options[choice.taken]
+			}
+		};
+
+		if stays && next != current {
+			self.preemptions += 1;
+		}
+		self.threads[next].status = Status::Runnable;
+		Some(next)
+	}
+
+	fn deadlock(&self) -> String {
+		let threads = self.threads.iter().enumerate();
+		let stuck: Vec<String> = threads
+			.filter(|(_, thread)| thread.status != Status::Finished)
+			.map(|(id, thread)| format!("thread {} {:?}", id, thread.status))
+			.collect();
+		format!("deadlock, no thread can make progress (lost wakeup?): {}", stuck.join(", "))
+	}
+
+	fn record_atomic(&mut self, me: usize, addr: usize, access: Access) {
+		let State { threads, atomics, epoch, .. } = self;
+		let thread = &mut threads[me];
+		thread.clock.tick(me);
+		let published = atomics.entry(addr).or_default();
+
+		let (acquire, release) = match access {
+			Access::Load(order) => (Some(order), None),
+			Access::Store(order) => (None, Some(order)),
+			Access::Rmw(order) => (Some(order), Some(order)),
+		};
+		if let Some(order) = acquire {
+			match acquires(order) {
+				true => thread.clock.join(published),
+				false => thread.acquire_fence.join(published),
+			}
+		}
+		if let Some(order) = release {
+			*epoch += 1;
+			let clock = if releases(order) { &thread.clock } else { &thread.release_fence };
+			// A read-modify-write continues the release sequence of the value it replaced
+			match access {
+				Access::Rmw(_) => published.join(clock),
+				_ => *published = clock.clone(),
+			}
+		}
+	}
+
+	fn record_fence(&mut self, me: usize, order: Ordering) {
+		let thread = &mut self.threads[me];
+		if acquires(order) {
+			let published = mem::take(&mut thread.acquire_fence);
+			thread.clock.join(&published);
+		}
+		if releases(order) {
+			thread.release_fence = thread.clock.clone();
+		}
+	}
+
+	fn record_cell(&mut self, me: usize, addr: usize, write: bool) -> Result<(), String> {
+		let clock = &mut self.threads[me].clock;
+		let now = clock.tick(me);
+		let cell = self.cells.entry(addr).or_default();
+		let race = |other: usize| {
+			format!("data race on {:#x} between thread {} and thread {}", addr, other, me)
+		};
+
+		if let Some((writer, at)) = cell.write {
+			if !clock.has_seen(writer, at) {
+				return Err(race(writer));
+			}
+		}
+		if !write {
+			cell.reads.0[me] = now;
+			return Ok(());
+		}
+
+		if let Some(reader) = (0..MAX_THREADS).find(|&t| !clock.has_seen(t, cell.reads.0[t])) {
+			return Err(race(reader));
+		}
+		cell.write = Some((me, now));
+		cell.reads = VClock::default();
+		Ok(())
+	}
+}
+
+fn acquires(order: Ordering) -> bool {
+	matches!(order, Ordering::Acquire | Ordering::AcqRel | Ordering::SeqCst)
+}
+
+fn releases(order: Ordering) -> bool {
+	matches!(order, Ordering::Release | Ordering::AcqRel | Ordering::SeqCst)
+}
+
+/// One run of the model. Its threads are real threads, but only the `active` one runs, the
+/// others wait on `wake` for their turn
+#[derive(Debug)]
+struct Execution {
+	state: Mutex<State>,
+	wake: Condvar,
+	threads: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+
+impl Execution {
+	fn new(path: Vec<Choice>, preemption_bound: usize) -> Execution {
+		let state = State {
+			threads: vec![Thread::new(VClock::default())],
+			active: 0,
+			path,
+			depth: 0,
+			preemptions: 0,
+			preemption_bound,
+			steps: 0,
+			epoch: 0,
+			atomics: HashMap::new(),
+			cells: HashMap::new(),
+			failure: None,
+		};
+		Execution {
+			state: Mutex::new(state),
+			wake: Condvar::new(),
+			threads: Mutex::new(Vec::new()),
+		}
+	}
+
+	fn lock(&self) -> MutexGuard<'_, State> {
+		self.state.lock().unwrap_or_else(|err| err.into_inner())
+	}
+
+	/// Records the first failure of the execution and wakes every thread so they unwind
+	fn fail(&self, state: &mut State, message: String) {
+		state.failure.get_or_insert(message);
+		self.wake.notify_all();
+	}
+
+	/// Lets the scheduler pick who runs next, then waits until it is `me` again
+	fn switch(&self, mut state: MutexGuard<'_, State>, me: usize) {
+		if state.failure.is_none() {
+			state.steps += 1;
+			if state.steps > MAX_STEPS {
+				let message = format!("livelock, no end after {} scheduling points", MAX_STEPS);
+				self.fail(&mut state, message);
+			} else if let Some(next) = state.schedule(me) {
+				state.active = next;
+				self.wake.notify_all();
+			} else {
+				let message = state.deadlock();
+				self.fail(&mut state, message);
+			}
+		}
+		self.wait_turn(state, me);
+	}
+
+	fn wait_turn(&self, mut state: MutexGuard<'_, State>, me: usize) {
+		while state.active != me && state.failure.is_none() {
+			state = self.wake.wait(state).unwrap_or_else(|err| err.into_inner());
+		}
+		if state.failure.is_some() {
+			drop(state);
+			panic::resume_unwind(Box::new(Abort));
+		}
+	}
+}
+
+/// The execution and thread id of the calling model thread. `None` on other threads, and while
+/// unwinding, when the lock types run unchecked
+fn current() -> Option<(Arc<Execution>, usize)> {
+	if thread::panicking() {
+		return None;
+	}
+	CURRENT.try_with(|current| current.borrow().clone()).ok().flatten()
+}
+
+fn run(execution: Arc<Execution>, id: usize, f: impl FnOnce()) {
+	CURRENT.with(|current| *current.borrow_mut() = Some((execution.clone(), id)));
+	let result = panic::catch_unwind(AssertUnwindSafe(|| {
+		execution.wait_turn(execution.lock(), id);
+		f()
+	}));
+	CURRENT.with(|current| current.borrow_mut().take());
+
+	let mut state = execution.lock();
+	state.threads[id].status = Status::Finished;
+	if let Err(payload) = result {
+		if !payload.is::<Abort>() {
+			let message = payload
+				.downcast_ref::<&str>()
+				.map(|message| message.to_string())
+				.or_else(|| payload.downcast_ref::<String>().cloned())
+				.unwrap_or_default();
+			execution.fail(&mut state, format!("thread {} panicked: {}", id, message));
+		}
+	}
+
+	if state.failure.is_none() {
+		if let Some(next) = state.schedule(id) {
+			state.active = next;
+		} else if state.threads.iter().any(|thread| thread.status != Status::Finished) {
+			let message = state.deadlock();
+			execution.fail(&mut state, message);
+		}
+	}
+	execution.wake.notify_all();
+}
+
+/// Runs `f` under every interleaving of the model threads it spawns, within the default
+/// preemption bound. Panics with the failing schedule on a data race, a deadlock or lost wakeup,
+/// a livelock or a panic in any thread
+pub fn check(f: impl Fn() + Send + Sync + 'static) { Builder::new().check(f); }
+
+/// Configures the search done by [`check`]
+#[derive(Debug, Clone)]
+pub struct Builder {
+	preemption_bound: usize,
+}
+
+impl Builder {
+	pub fn new() -> Builder { Builder { preemption_bound: DEFAULT_PREEMPTION_BOUND } }
+
+	/// Times an execution may switch away from a thread that could have kept running
+	pub fn preemption_bound(mut self, bound: usize) -> Builder {
+		self.preemption_bound = bound;
+		self
+	}
+
+	/// Explores the interleavings of `f` depth first, one execution each. Returns how many
+	/// executions there were
+	///
+	/// Every interleaving runs sequentially consistent, but each atomic carries the vector clock
+	/// of the stores it publishes, so accesses to [`Tracked`] values are only ordered by the
+	/// orderings the code actually asked for. A missing `Acquire` or `Release` is a data race
+	pub fn check(&self, f: impl Fn() + Send + Sync + 'static) -> usize {
+		let f = Arc::new(f);
+		let mut path = Vec::new();
+		let mut executions = 0;
+
+		loop {
+			executions += 1;
+			let execution = Arc::new(Execution::new(path, self.preemption_bound));
+			let main = {
+				let (execution, f) = (execution.clone(), f.clone());
+				thread::spawn(move || run(execution, 0, move || f()))
+			};
+			execution.threads.lock().unwrap().push(main);
+			loop {
+				let next = execution.threads.lock().unwrap().pop();
+				match next {
+					Some(thread) => thread.join().unwrap(),
+					None => break,
+				}
+			}
+
+			let mut state = execution.lock();
+			if let Some(failure) = state.failure.take() {
+				let schedule: Vec<usize> = state.path[..state.depth]
+					.iter()
+					.map(|choice| choice.options[choice.taken])
+					.collect();
+				panic!("{}\n  execution {}, schedule {:?}", failure, executions, schedule);
+			}
+
+			path = mem::take(&mut state.path);
+			while path.last().is_some_and(|choice| choice.taken + 1 == choice.options.len()) {
+				path.pop();
+			}
+			match path.last_mut() {
+				Some(choice) => choice.taken += 1,
+				None => return executions,
+			}
+		}
+	}
+}
+
+impl Default for Builder {
+	fn default() -> Self { Self::new() }
+}
+
+/// Starts a model thread. Only callable from within [`check`]
+pub fn spawn<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> JoinHandle<T> {
+	let (execution, me) = current().expect("model::spawn called outside model::check");
+	let id = {
+		let mut state = execution.lock();
+		let id = state.threads.len();
+		assert!(id < MAX_THREADS, "a model runs at most {} threads", MAX_THREADS);
+		let parent = &mut state.threads[me];
+		parent.clock.tick(me);
+		let clock = parent.clock.clone();
+		state.threads.push(Thread::new(clock));
+		id
+	};
+
+	let result = Arc::new(Mutex::new(None));
+	let thread = {
+		let (execution, result) = (execution.clone(), result.clone());
+		thread::spawn(move || run(execution, id, move || *result.lock().unwrap() = Some(f())))
+	};
+	execution.threads.lock().unwrap().push(thread);
+	JoinHandle { id, result }
+}
+
+#[derive(Debug)]
+pub struct JoinHandle<T> {
+	id: usize,
+	result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+	/// Waits for the thread to finish, everything it did happens before the return
+	pub fn join(self) -> T {
+		let (execution, me) = current().expect("JoinHandle::join called outside model::check");
+		let mut state = execution.lock();
+		if state.threads[self.id].status != Status::Finished {
+			state.threads[me].status = Status::Joining(self.id);
+			execution.switch(state, me);
+			state = execution.lock();
+		}
+
+		let finished = state.threads[self.id].clock.clone();
+		state.threads[me].clock.join(&finished);
+		drop(state);
+		self.result.lock().unwrap().take().expect("model thread finished without a result")
+	}
+}
+
+/// Runs atomic operation `op` on the atomic at `addr`, after letting any other thread go first
+pub(crate) fn atomic<R>(addr: usize, op: impl FnOnce() -> (R, Access)) -> R {
+	let Some((execution, me)) = current() else { return op().0 };
+	execution.switch(execution.lock(), me);
+	let (result, access) = op();
+	execution.lock().record_atomic(me, addr, access);
+	result
+}
+
+pub(crate) fn fence(order: Ordering) {
+	match current() {
+		Some((execution, me)) => execution.lock().record_fence(me, order),
+		None => std::sync::atomic::fence(order),
+	}
+}
+
+/// Parks the calling model thread until another one writes an atomic. If none can, the thread
+/// waits for a wakeup that never comes and the execution fails.
+///
+/// Other threads yield instead, unlike a core the host may have preempted the lock holder
+pub(crate) fn spin() {
+	let Some((execution, me)) = current() else { return thread::yield_now() };
+	let mut state = execution.lock();
+	let thread = &mut state.threads[me];
+	thread.status = Status::Spinning { epoch: thread.resumed };
+	execution.switch(state, me);
+
+	let mut state = execution.lock();
+	state.threads[me].resumed = state.epoch;
+}
+
+/// A value the model threads share through the primitive under test. Every access is checked
+/// against the happens-before order established by the atomics, two accesses from different
+/// threads that are not ordered and not both reads fail the execution with a data race
+#[derive(Debug, Default)]
+pub struct Tracked<T> {
+	value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Tracked<T> {}
+
+impl<T> Tracked<T> {
+	pub const fn new(value: T) -> Tracked<T> { Tracked { value: UnsafeCell::new(value) } }
+
+	pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+		self.access(false);
+		f(unsafe { &*self.value.get() })
+	}
+
+	pub fn with_mut<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+		self.access(true);
+		f(unsafe { &mut *self.value.get() })
+	}
+
+	pub fn into_inner(self) -> T { self.value.into_inner() }
+
+	fn access(&self, write: bool) {
+		let Some((execution, me)) = current() else { return };
+		let mut state = execution.lock();
+		if let Err(message) = state.record_cell(me, self as *const _ as usize, write) {
+			execution.fail(&mut state, message);
+			drop(state);
+			panic::resume_unwind(Box::new(Abort));
+		}
+	}
+}
@@ -0,0 +1,137 @@
+// Stand-in for `kernel/src/sync.rs`. The atomics report every operation to the model checker when
+// used from a model thread and behave like the ones in `core` everywhere else
+
+use crate::model;
+
+pub fn spin_loop() { model::spin() }
+
+pub mod atomic {
+	use core::{fmt, sync::atomic as core_atomic};
+
+	pub use core::sync::atomic::Ordering;
+
+	use crate::model::{self, Access};
+
+	pub fn fence(order: Ordering) { model::fence(order) }
+
+	/// Methods every atomic has, `$value` is the type it holds
+	macro_rules! atomic_common {
+		($value:ty) => {
+			pub fn get_mut(&mut self) -> &mut $value { self.0.get_mut() }
+
+			pub fn into_inner(self) -> $value { self.0.into_inner() }
+
+			pub fn load(&self, order: Ordering) -> $value {
+				model::atomic(self.addr(), || (self.0.load(order), Access::Load(order)))
+			}
+
+			pub fn store(&self, value: $value, order: Ordering) {
+				model::atomic(self.addr(), || (self.0.store(value, order), Access::Store(order)))
+			}
+
+			pub fn swap(&self, value: $value, order: Ordering) -> $value {
+				model::atomic(self.addr(), || (self.0.swap(value, order), Access::Rmw(order)))
+			}
+
+			pub fn compare_exchange(
+				&self,
+				current: $value,
+				new: $value,
+				success: Ordering,
+				failure: Ordering,
+			) -> Result<$value, $value> {
+				model::atomic(self.addr(), || {
+					let result = self.0.compare_exchange(current, new, success, failure);
+					let access = match result {
+						Ok(_) => Access::Rmw(success),
+						Err(_) => Access::Load(failure),
+					};
+					(result, access)
+				})
+			}
+
+			/// Never fails spuriously, not even outside the model
+			pub fn compare_exchange_weak(
+				&self,
+				current: $value,
+				new: $value,
+				success: Ordering,
+				failure: Ordering,
+			) -> Result<$value, $value> {
+				self.compare_exchange(current, new, success, failure)
+			}
+
+			fn addr(&self) -> usize { self as *const Self as usize }
+		};
+	}
+
+	macro_rules! fetch_ops {
+		($value:ty, $($op:ident),*) => {$(
+			pub fn $op(&self, value: $value, order: Ordering) -> $value {
+				model::atomic(self.addr(), || (self.0.$op(value, order), Access::Rmw(order)))
+			}
+		)*};
+	}
+
+	macro_rules! atomic_int {
+		($($atomic:ident($int:ty)),*) => {$(
+			#[derive(Debug, Default)]
+			#[repr(transparent)]
+			pub struct $atomic(core_atomic::$atomic);
+
+			impl $atomic {
+				pub const fn new(value: $int) -> $atomic { $atomic(core_atomic::$atomic::new(value)) }
+
+				atomic_common!($int);
+				fetch_ops!($int, fetch_add, fetch_sub, fetch_and, fetch_or, fetch_xor, fetch_max, fetch_min);
+			}
+		)*};
+	}
+
+	atomic_int!(AtomicU8(u8), AtomicU16(u16), AtomicU32(u32), AtomicU64(u64), AtomicUsize(usize));
+	atomic_int!(AtomicI8(i8), AtomicI16(i16), AtomicI32(i32), AtomicI64(i64), AtomicIsize(isize));
+
+	#[derive(Debug, Default)]
+	#[repr(transparent)]
+	pub struct AtomicBool(core_atomic::AtomicBool);
+
+	impl AtomicBool {
+		atomic_common!(bool);
+
+		fetch_ops!(bool, fetch_and, fetch_or, fetch_xor);
+
+		pub const fn new(value: bool) -> AtomicBool {
+			AtomicBool(core_atomic::AtomicBool::new(value))
+		}
+	}
+
+	#[repr(transparent)]
+	pub struct AtomicPtr<T>(core_atomic::AtomicPtr<T>);
+
+	impl<T> AtomicPtr<T> {
+		atomic_common!(*mut T);
+
+		pub const fn new(value: *mut T) -> AtomicPtr<T> {
+			AtomicPtr(core_atomic::AtomicPtr::new(value))
+		}
+	}
+
+	impl<T> fmt::Debug for AtomicPtr<T> {
+		fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { self.0.fmt(f) }
+	}
+}
+
+/// Interrupts are a flag of the calling thread on the host
+pub mod interrupts {
+	use std::cell::Cell;
+
+	std::thread_local! {
+		static ENABLED: Cell<bool> = const { Cell::new(true) };
+	}
+
+	pub fn are_enabled() -> bool { ENABLED.get() }
+
+	pub fn enable() { ENABLED.set(true) }
+
+	pub fn disable() { ENABLED.set(false) }
+}
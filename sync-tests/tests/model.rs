@@ -0,0 +1,419 @@
+use std::{
+	collections::HashSet,
+	sync::Arc,
+	task::{Wake, Waker},
+};
+
+use sync_tests::{
+	atomic_waker::AtomicWaker,
+	model::{self, Builder, Tracked},
+	mutex::{IrqMutex, Mutex, RwLock, TicketMutex},
+	once_lock::OnceLock,
+	ring::SpscRing,
+	sync::{
+		atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+		spin_loop,
+	},
+};
+
+#[test]
+fn mutex_excludes() {
+	model::check(|| {
+		let lock = Arc::new(Mutex::new(Tracked::new(0)));
+		let other = {
+			let lock = lock.clone();
+			model::spawn(move || lock.lock().with_mut(|count| *count += 1))
+		};
+		lock.lock().with_mut(|count| *count += 1);
+		other.join();
+		assert_eq!(lock.lock().with(|count| *count), 2);
+	});
+}
+
+#[test]
+fn mutex_try_lock_excludes() {
+	model::check(|| {
+		let lock = Arc::new(Mutex::new(Tracked::new(0)));
+		let other = {
+			let lock = lock.clone();
+			model::spawn(move || lock.try_lock().map(|guard| guard.with_mut(|count| *count += 1)))
+		};
+		let mine = lock.try_lock().map(|guard| guard.with_mut(|count| *count += 1));
+		let taken = other.join().is_some() as usize + mine.is_some() as usize;
+		assert!(taken >= 1);
+		assert_eq!(lock.lock().with(|count| *count), taken);
+	});
+}
+
+#[test]
+fn ticket_mutex_excludes() {
+	model::check(|| {
+		let lock = Arc::new(TicketMutex::new(Tracked::new(0)));
+		let others: Vec<_> = (0..2)
+			.map(|_| {
+				let lock = lock.clone();
+				model::spawn(move || lock.lock().with_mut(|count| *count += 1))
+			})
+			.collect();
+		lock.lock().with_mut(|count| *count += 1);
+		others.into_iter().for_each(model::JoinHandle::join);
+		assert_eq!(lock.lock().with(|count| *count), 3);
+	});
+}
+
+#[test]
+fn irq_mutex_excludes() {
+	model::check(|| {
+		let lock = Arc::new(IrqMutex::new(Tracked::new(0)));
+		let other = {
+			let lock = lock.clone();
+			model::spawn(move || lock.lock().with_mut(|count| *count += 1))
+		};
+		lock.lock().with_mut(|count| *count += 1);
+		other.join();
+		assert_eq!(lock.lock().with(|count| *count), 2);
+	});
+}
+
+#[test]
+fn rwlock_readers_share_writers_exclude() {
+	model::check(|| {
+		let lock = Arc::new(RwLock::new(Tracked::new(0)));
+		let reader = {
+			let lock = lock.clone();
+			model::spawn(move || lock.read().with(|value| *value))
+		};
+		let writer = {
+			let lock = lock.clone();
+			model::spawn(move || lock.write().with_mut(|value| *value += 1))
+		};
+		let seen = lock.read().with(|value| *value);
+		assert!(seen <= 1);
+		assert!(reader.join() <= 1);
+		writer.join();
+		assert_eq!(lock.read().with(|value| *value), 1);
+	});
+}
+
+#[test]
+fn rwlock_try_write_excludes_readers() {
+	model::check(|| {
+		let lock = Arc::new(RwLock::new(Tracked::new(0)));
+		let writer = {
+			let lock = lock.clone();
+			model::spawn(move || lock.try_write().map(|guard| guard.with_mut(|value| *value += 1)))
+		};
+		let read = lock.try_read().map(|guard| guard.with(|value| *value));
+		let wrote = writer.join().is_some();
+		assert!(wrote || read.is_some());
+		assert_eq!(lock.read().with(|value| *value), wrote as i32);
+	});
+}
+
+#[test]
+fn once_lock_set_publishes() {
+	model::check(|| {
+		let payload = Arc::new(Tracked::new(0));
+		let lock = Arc::new(OnceLock::new());
+		let setter = {
+			let (payload, lock) = (payload.clone(), lock.clone());
+			model::spawn(move || {
+				payload.with_mut(|value| *value = 42);
+				lock.set(()).unwrap();
+			})
+		};
+		if lock.get().is_some() {
+			assert_eq!(payload.with(|value| *value), 42);
+		}
+		setter.join();
+	});
+}
+
+#[test]
+fn once_lock_set_once() {
+	model::check(|| {
+		let lock = Arc::new(OnceLock::new());
+		let other = {
+			let lock = lock.clone();
+			model::spawn(move || lock.set(1).is_ok())
+		};
+		let mine = lock.set(2).is_ok();
+		assert!(other.join() != mine);
+		assert_eq!(*lock.get().unwrap(), if mine { 2 } else { 1 });
+	});
+}
+
+#[test]
+fn once_lock_get_or_init_runs_once() {
+	model::check(|| {
+		let runs = Arc::new(AtomicUsize::new(0));
+		let lock = Arc::new(OnceLock::new());
+		let init = |runs: &AtomicUsize| {
+			runs.fetch_add(1, Ordering::Relaxed);
+			Tracked::new(7)
+		};
+		let other = {
+			let (runs, lock) = (runs.clone(), lock.clone());
+			model::spawn(move || lock.get_or_init(|| init(&runs)).with(|value| *value))
+		};
+		assert_eq!(lock.get_or_init(|| init(&runs)).with(|value| *value), 7);
+		assert_eq!(other.join(), 7);
+		assert_eq!(runs.load(Ordering::Relaxed), 1);
+	});
+}
+
+#[test]
+fn once_lock_failed_init_retried() {
+	model::check(|| {
+		let lock = Arc::new(OnceLock::new());
+		let failing = {
+			let lock = lock.clone();
+			model::spawn(move || lock.get_or_try_init(|| Err::<usize, ()>(())).is_err())
+		};
+		assert_eq!(*lock.get_or_init(|| 1), 1);
+		failing.join();
+		assert_eq!(lock.get(), Some(&1));
+	});
+}
+
+#[test]
+fn once_lock_wait_wakes() {
+	model::check(|| {
+		let lock = Arc::new(OnceLock::new());
+		let waiter = {
+			let lock = lock.clone();
+			model::spawn(move || *lock.wait())
+		};
+		lock.set(5).unwrap();
+		assert_eq!(waiter.join(), 5);
+	});
+}
+
+#[test]
+fn explores_every_interleaving() {
+	// Two threads doing two stores each interleave in 4 choose 2 ways
+	let orders = Arc::new(std::sync::Mutex::new(HashSet::new()));
+	let seen = orders.clone();
+	Builder::new().preemption_bound(usize::MAX).check(move || {
+		let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+		let counter = Arc::new(AtomicUsize::new(0));
+		let run =
+			move |thread: usize, order: &std::sync::Mutex<Vec<usize>>, counter: &AtomicUsize| {
+				for _ in 0..2 {
+					counter.fetch_add(1, Ordering::Relaxed);
+					order.lock().unwrap().push(thread);
+				}
+			};
+		let other = {
+			let (order, counter) = (order.clone(), counter.clone());
+			model::spawn(move || run(1, &order, &counter))
+		};
+		run(0, &order, &counter);
+		other.join();
+		seen.lock().unwrap().insert(order.lock().unwrap().clone());
+	});
+	assert_eq!(orders.lock().unwrap().len(), 6);
+}
+
+#[test]
+#[should_panic(expected = "data race")]
+fn finds_relaxed_publication() {
+	model::check(|| {
+		let payload = Arc::new(Tracked::new(0));
+		let ready = Arc::new(AtomicBool::new(false));
+		let writer = {
+			let (payload, ready) = (payload.clone(), ready.clone());
+			model::spawn(move || {
+				payload.with_mut(|value| *value = 1);
+				ready.store(true, Ordering::Relaxed);
+			})
+		};
+		if ready.load(Ordering::Acquire) {
+			payload.with(|value| *value);
+		}
+		writer.join();
+	});
+}
+
+#[test]
+#[should_panic(expected = "data race")]
+fn finds_unfenced_relaxed_lock() {
+	// Same shape as `Mutex::lock`, without its acquire fence after the relaxed exchange
+	model::check(|| {
+		let locked = Arc::new(AtomicBool::new(false));
+		let payload = Arc::new(Tracked::new(0));
+		let critical = |locked: &AtomicBool, payload: &Tracked<i32>| {
+			while locked
+				.compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+				.is_err()
+			{
+				spin_loop();
+			}
+			payload.with_mut(|value| *value += 1);
+			locked.store(false, Ordering::Release);
+		};
+		let other = {
+			let (locked, payload) = (locked.clone(), payload.clone());
+			model::spawn(move || critical(&locked, &payload))
+		};
+		critical(&locked, &payload);
+		other.join();
+	});
+}
+
+#[test]
+#[should_panic(expected = "lost wakeup")]
+fn finds_lost_wakeup() {
+	// The waiter checks `ready` before the notifier sets it and goes to sleep on `asleep` for good
+	model::check(|| {
+		let ready = Arc::new(AtomicBool::new(false));
+		let asleep = Arc::new(AtomicBool::new(false));
+		let waiter = {
+			let (ready, asleep) = (ready.clone(), asleep.clone());
+			model::spawn(move || {
+				if !ready.load(Ordering::Acquire) {
+					asleep.store(true, Ordering::Relaxed);
+					while asleep.load(Ordering::Acquire) {
+						spin_loop();
+					}
+				}
+			})
+		};
+		ready.store(true, Ordering::Release);
+		if asleep.load(Ordering::Relaxed) {
+			asleep.store(false, Ordering::Release);
+		}
+		waiter.join();
+	});
+}
+
+/// The parking handshake of `kernel::thread`, a thread's `state` and `unparked` flag
+#[derive(Debug)]
+struct Parking {
+	state: AtomicU8,
+	unparked: AtomicBool,
+}
+
+impl Parking {
+	const BLOCKED: u8 = 2;
+	const READY: u8 = 1;
+	const RUNNING: u8 = 0;
+
+	/// `Thread::block`, run by the thread itself. Returns whether it leaves the core
+	fn block(&self) -> bool {
+		self.state.store(Parking::BLOCKED, Ordering::SeqCst);
+		if !self.unparked.swap(false, Ordering::SeqCst) {
+			return true;
+		}
+
+		let (blocked, running) = (Parking::BLOCKED, Parking::RUNNING);
+		self.state.compare_exchange(blocked, running, Ordering::SeqCst, Ordering::Relaxed).is_err()
+	}
+
+	/// `unpark`, returns whether it queued the thread
+	fn unpark(&self) -> bool {
+		self.unparked.store(true, Ordering::SeqCst);
+		let (blocked, ready) = (Parking::BLOCKED, Parking::READY);
+		let queued = self
+			.state
+			.compare_exchange(blocked, ready, Ordering::SeqCst, Ordering::Relaxed)
+			.is_ok();
+		if queued {
+			self.unparked.store(false, Ordering::Relaxed);
+		}
+		queued
+	}
+}
+
+#[test]
+fn park_unpark_handshake() {
+	// A thread that blocks must be queued exactly once, one that keeps running must not be
+	model::check(|| {
+		let parking = Arc::new(Parking {
+			state: AtomicU8::new(Parking::RUNNING),
+			unparked: AtomicBool::new(false),
+		});
+		let waker = {
+			let parking = parking.clone();
+			model::spawn(move || parking.unpark())
+		};
+		let blocked = parking.block();
+		let queued = waker.join();
+		assert_eq!(blocked, queued, "lost or doubled wakeup");
+		let expected = if queued { Parking::READY } else { Parking::RUNNING };
+		assert_eq!(parking.state.load(Ordering::Relaxed), expected);
+	});
+}
+
+#[test]
+fn ring_hands_over_in_order() {
+	model::check(|| {
+		let payloads = Arc::new([Tracked::new(0), Tracked::new(0), Tracked::new(0)]);
+		let ring = Arc::new(SpscRing::<usize, 2>::new());
+		let producer = {
+			let (payloads, ring) = (payloads.clone(), ring.clone());
+			model::spawn(move || {
+				let mut pushed = Vec::new();
+				for (index, payload) in payloads.iter().enumerate() {
+					payload.with_mut(|value| *value = index + 10);
+					if unsafe { ring.push(index) }.is_ok() {
+						pushed.push(index);
+					}
+				}
+				pushed
+			})
+		};
+
+		let mut popped = Vec::new();
+		for _ in 0..2 {
+			if let Some(index) = unsafe { ring.pop() } {
+				assert_eq!(payloads[index].with(|value| *value), index + 10);
+				popped.push(index);
+			}
+		}
+		let pushed = producer.join();
+		popped.extend(std::iter::from_fn(|| unsafe { ring.pop() }));
+		assert_eq!(popped, pushed);
+		assert_eq!(ring.dropped() as usize, 3 - pushed.len());
+	});
+}
+
+/// Waker recording that it was called
+#[derive(Debug, Default)]
+struct Woken(AtomicBool);
+
+impl Wake for Woken {
+	fn wake(self: Arc<Self>) { self.0.store(true, Ordering::Release) }
+}
+
+#[test]
+fn atomic_waker_never_loses_a_wake() {
+	model::check(|| {
+		let slot = Arc::new(AtomicWaker::new());
+		let events = Arc::new(AtomicUsize::new(0));
+		let producer = {
+			let (slot, events) = (slot.clone(), events.clone());
+			model::spawn(move || {
+				for _ in 0..2 {
+					events.fetch_add(1, Ordering::Release);
+					slot.wake();
+				}
+			})
+		};
+
+		// Two polls: register a fresh waker, then look for events. Only the last waker is owed
+		// a wake, for any event after what that poll saw
+		let mut last = None;
+		for _ in 0..2 {
+			let woken = Arc::new(Woken::default());
+			slot.register(&Waker::from(woken.clone()));
+			last = Some((woken, events.load(Ordering::Acquire)));
+		}
+		producer.join();
+
+		let (woken, seen) = last.unwrap();
+		if seen < 2 {
+			assert!(woken.0.load(Ordering::Acquire), "a wake was lost");
+		}
+	});
+}
@@ -0,0 +1,332 @@
+use std::{
+	collections::VecDeque,
+	sync::{Arc, Barrier},
+	thread,
+};
+
+use sync_tests::{
+	blocking::{self, Condvar, Semaphore},
+	mutex::{IrqMutex, Mutex, RwLock, TicketMutex},
+	once_lock::{Lazy, OnceLock},
+	ring::SpscRing,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		interrupts,
+	},
+	wait_queue::WaitQueue,
+};
+
+const THREADS: usize = 8;
+const ITERATIONS: usize = 20_000;
+
+/// Runs `f` on [`THREADS`] threads released at once, passing each its index
+fn hammer(f: impl Fn(usize) + Send + Sync + 'static) {
+	let f = Arc::new(f);
+	let start = Arc::new(Barrier::new(THREADS));
+	let threads: Vec<_> = (0..THREADS)
+		.map(|index| {
+			let (f, start) = (f.clone(), start.clone());
+			thread::spawn(move || {
+				start.wait();
+				f(index)
+			})
+		})
+		.collect();
+	for thread in threads {
+		thread.join().unwrap();
+	}
+}
+
+/// A counter updated in two halves, so a lost exclusion shows up as a torn pair
+#[derive(Debug, Default)]
+struct Pair(usize, usize);
+
+impl Pair {
+	fn bump(&mut self) {
+		let first = self.0;
+		self.0 = first + 1;
+		std::hint::black_box(&mut *self);
+		assert_eq!(self.1, first, "another thread is inside the critical section");
+		self.1 = first + 1;
+	}
+}
+
+#[test]
+fn mutex_counts() {
+	let lock = Arc::new(Mutex::new(Pair::default()));
+	let shared = lock.clone();
+	hammer(move |_| (0..ITERATIONS).for_each(|_| shared.lock().bump()));
+	assert_eq!(lock.lock().0, THREADS * ITERATIONS);
+}
+
+#[test]
+fn mutex_try_lock_counts() {
+	let lock = Arc::new(Mutex::new(Pair::default()));
+	let taken = Arc::new(AtomicUsize::new(0));
+	let (shared, counted) = (lock.clone(), taken.clone());
+	hammer(move |_| {
+		for _ in 0..ITERATIONS {
+			if let Some(mut guard) = shared.try_lock() {
+				guard.bump();
+				counted.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+	});
+	assert_eq!(lock.lock().0, taken.load(Ordering::Relaxed));
+}
+
+#[test]
+fn mutex_lock_timeout_expires() {
+	let lock = Mutex::new(());
+	let _held = lock.lock();
+	assert!(lock.try_lock().is_none());
+	assert!(lock.lock_timeout(10).is_none());
+}
+
+#[test]
+fn ticket_mutex_counts() {
+	let lock = Arc::new(TicketMutex::new(Pair::default()));
+	let shared = lock.clone();
+	hammer(move |_| (0..ITERATIONS).for_each(|_| shared.lock().bump()));
+	assert_eq!(lock.lock().0, THREADS * ITERATIONS);
+}
+
+#[test]
+fn irq_mutex_restores_interrupts() {
+	let lock = Arc::new(IrqMutex::new(Pair::default()));
+	let shared = lock.clone();
+	hammer(move |index| {
+		// Half the threads start with interrupts disabled, which the lock must keep that way
+		if index % 2 == 1 {
+			interrupts::disable();
+		}
+		let enabled = interrupts::are_enabled();
+		for _ in 0..ITERATIONS {
+			let mut guard = shared.lock();
+			assert!(!interrupts::are_enabled());
+			guard.bump();
+			drop(guard);
+			assert_eq!(interrupts::are_enabled(), enabled);
+		}
+	});
+	assert_eq!(lock.lock().0, THREADS * ITERATIONS);
+}
+
+#[test]
+fn rwlock_counts() {
+	let lock = Arc::new(RwLock::new(Pair::default()));
+	let shared = lock.clone();
+	hammer(move |index| {
+		for _ in 0..ITERATIONS {
+			if index % 2 == 0 {
+				shared.write().bump();
+			} else {
+				let guard = shared.read();
+				assert_eq!(guard.0, guard.1, "a writer is inside the critical section");
+			}
+		}
+	});
+	assert_eq!(lock.read().0, THREADS / 2 * ITERATIONS);
+}
+
+#[test]
+fn rwlock_timeouts_expire() {
+	let lock = RwLock::new(());
+	let read = lock.read();
+	assert!(lock.write_timeout(10).is_none());
+	assert!(lock.read_timeout(10).is_some());
+	drop(read);
+
+	let _write = lock.write();
+	assert!(lock.try_read().is_none());
+	assert!(lock.read_timeout(10).is_none());
+}
+
+#[test]
+fn once_lock_initializes_once() {
+	for _ in 0..100 {
+		let lock = Arc::new(OnceLock::new());
+		let runs = Arc::new(AtomicUsize::new(0));
+		let (shared, counted) = (lock.clone(), runs.clone());
+		hammer(move |index| {
+			let value = shared.get_or_init(|| {
+				counted.fetch_add(1, Ordering::Relaxed);
+				index
+			});
+			assert_eq!(shared.wait(), value);
+		});
+		assert_eq!(runs.load(Ordering::Relaxed), 1);
+		assert!(lock.get().is_some());
+	}
+}
+
+#[test]
+fn once_lock_set_races() {
+	for _ in 0..100 {
+		let lock = Arc::new(OnceLock::new());
+		let won = Arc::new(AtomicUsize::new(0));
+		let (shared, counted) = (lock.clone(), won.clone());
+		hammer(move |index| {
+			if shared.set(index).is_ok() {
+				counted.fetch_add(1, Ordering::Relaxed);
+			}
+		});
+		assert_eq!(won.load(Ordering::Relaxed), 1);
+	}
+}
+
+#[test]
+fn once_lock_take_empties() {
+	let mut lock = OnceLock::new();
+	assert_eq!(lock.take(), None);
+	lock.set(String::from("value")).unwrap();
+	assert_eq!(lock.take().as_deref(), Some("value"));
+	assert!(lock.get().is_none());
+	lock.set(String::from("again")).unwrap();
+	assert_eq!(lock.into_inner().as_deref(), Some("again"));
+}
+
+static LAZY_RUNS: AtomicUsize = AtomicUsize::new(0);
+static LAZY: Lazy<usize> = Lazy::new(|| LAZY_RUNS.fetch_add(1, Ordering::Relaxed) + 10);
+
+#[test]
+fn lazy_initializes_once() {
+	assert_eq!(Lazy::get(&LAZY), None);
+	hammer(|_| assert_eq!(*LAZY, 10));
+	assert_eq!(LAZY_RUNS.load(Ordering::Relaxed), 1);
+	assert_eq!(Lazy::get(&LAZY), Some(&10));
+}
+
+#[test]
+fn ring_keeps_order() {
+	const VALUES: u32 = 200_000;
+	let ring = Arc::new(SpscRing::<u32, 64>::new());
+	let producer = {
+		let ring = ring.clone();
+		thread::spawn(move || {
+			for value in 0..VALUES {
+				while unsafe { ring.push(value) }.is_err() {
+					thread::yield_now();
+				}
+			}
+		})
+	};
+
+	let mut expected = 0;
+	while expected < VALUES {
+		match unsafe { ring.pop() } {
+			Some(value) => {
+				assert_eq!(value, expected);
+				expected += 1;
+			}
+			None => thread::yield_now(),
+		}
+	}
+	producer.join().unwrap();
+	assert!(ring.is_empty());
+	assert_eq!(ring.pushed(), VALUES as u64);
+}
+
+#[test]
+fn blocking_mutex_counts() {
+	let lock = Arc::new(blocking::Mutex::new(Pair::default()));
+	let shared = lock.clone();
+	hammer(move |_| (0..ITERATIONS).for_each(|_| shared.lock().bump()));
+	assert_eq!(lock.lock().0, THREADS * ITERATIONS);
+}
+
+#[test]
+fn blocking_mutex_lock_timeout_expires() {
+	let lock = blocking::Mutex::new(());
+	let _held = lock.lock();
+	assert!(lock.try_lock().is_none());
+	assert!(lock.lock_timeout(10).is_none());
+}
+
+#[test]
+fn semaphore_limits_holders() {
+	const PERMITS: usize = 3;
+	let semaphore = Arc::new(Semaphore::new(PERMITS));
+	let inside = Arc::new(AtomicUsize::new(0));
+	let (shared, counted) = (semaphore.clone(), inside.clone());
+	hammer(move |_| {
+		for _ in 0..ITERATIONS / 10 {
+			shared.acquire();
+			let holders = counted.fetch_add(1, Ordering::Relaxed) + 1;
+			assert!(holders <= PERMITS, "{holders} threads hold {PERMITS} permits");
+			counted.fetch_sub(1, Ordering::Relaxed);
+			shared.release();
+		}
+	});
+	assert_eq!(semaphore.available(), PERMITS);
+	assert!(semaphore.acquire_timeout(10));
+}
+
+#[test]
+fn semaphore_timeout_expires() {
+	let semaphore = Semaphore::new(0);
+	assert!(!semaphore.try_acquire());
+	assert!(!semaphore.acquire_timeout(10));
+	semaphore.release();
+	assert!(semaphore.acquire_timeout(10));
+}
+
+#[test]
+fn condvar_hands_over_in_order() {
+	const VALUES: usize = 10_000;
+	let queue = Arc::new((blocking::Mutex::new(VecDeque::new()), Condvar::new()));
+	let producer = {
+		let queue = queue.clone();
+		thread::spawn(move || {
+			for value in 0..VALUES {
+				queue.0.lock().push_back(value);
+				queue.1.notify_one();
+			}
+		})
+	};
+
+	let (lock, ready) = &*queue;
+	for expected in 0..VALUES {
+		let mut values = ready.wait_while(lock.lock(), |values| values.is_empty());
+		assert_eq!(values.pop_front(), Some(expected));
+	}
+	producer.join().unwrap();
+}
+
+#[test]
+fn condvar_wait_timeout_expires() {
+	let lock = blocking::Mutex::new(());
+	let (_guard, timed_out) = Condvar::new().wait_timeout(lock.lock(), 10);
+	assert!(timed_out);
+}
+
+#[test]
+fn wait_queue_wakes_every_waiter() {
+	let queue = Arc::new(WaitQueue::new());
+	let released = Arc::new(AtomicBool::new(false));
+	let waiters: Vec<_> = (0..THREADS)
+		.map(|_| {
+			let (queue, released) = (queue.clone(), released.clone());
+			thread::spawn(move || queue.wait_until(|| released.load(Ordering::Relaxed)))
+		})
+		.collect();
+
+	released.store(true, Ordering::Relaxed);
+	queue.notify_all();
+	for waiter in waiters {
+		waiter.join().unwrap();
+	}
+	assert!(!queue.wait_until_timeout(|| false, 10));
+}
+
+#[test]
+fn huge_timeouts_saturate() {
+	let semaphore = Semaphore::new(1);
+	assert!(semaphore.acquire_timeout(u64::MAX));
+	assert!(blocking::Mutex::new(()).lock_timeout(u64::MAX).is_some());
+	assert!(WaitQueue::new().wait_until_timeout(|| true, u64::MAX));
+	assert!(Mutex::new(()).lock_timeout(u64::MAX).is_some());
+	let lock = RwLock::new(());
+	assert!(lock.read_timeout(u64::MAX).is_some());
+	assert!(lock.write_timeout(u64::MAX).is_some());
+}